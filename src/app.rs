@@ -1,14 +1,52 @@
-use crate::{compiler::Error as CompilerError, vm::Error as VMError};
+use std::{
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    compiler::{Compile, Compiler, Error as CompilerError},
+    lexer::Lexer,
+    vm::{Error as VMError, VirtualMachine},
+};
 
 #[derive(Debug, Clone)]
-enum ApplicationError {
-    CompileError(CompilerError),
-    VirtualmachineError(VMError),
+pub enum ApplicationError {
+    CompileError {
+        error: CompilerError,
+        source: Option<String>,
+    },
+    VirtualmachineError {
+        error: VMError,
+        source: Option<String>,
+    },
+    Read(String),
+}
+
+impl ApplicationError {
+    /// Attach the expression that failed, so it can be shown alongside the error.
+    fn with_source(self, src: &[u8]) -> Self {
+        let src = Some(String::from_utf8_lossy(src).trim().to_owned());
+        match self {
+            Self::CompileError { error, .. } => Self::CompileError { error, source: src },
+            Self::VirtualmachineError { error, .. } => {
+                Self::VirtualmachineError { error, source: src }
+            }
+            Self::Read(_) => self,
+        }
+    }
 }
 
 impl std::fmt::Display for ApplicationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        let (kind, error, source): (_, &dyn std::fmt::Display, _) = match self {
+            Self::CompileError { error, source } => ("Compiler error", error, source),
+            Self::VirtualmachineError { error, source } => ("Virtual machine error", error, source),
+            Self::Read(error) => return write!(f, "Read error: {}", error),
+        };
+        match source {
+            Some(src) => write!(f, "{} in \"{}\": {}", kind, src, error),
+            None => write!(f, "{}: {}", kind, error),
+        }
     }
 }
 
@@ -16,63 +54,372 @@ impl std::error::Error for ApplicationError {}
 
 impl From<CompilerError> for ApplicationError {
     fn from(value: CompilerError) -> Self {
-        Self::CompileError(value)
+        Self::CompileError {
+            error: value,
+            source: None,
+        }
     }
 }
 
 impl From<VMError> for ApplicationError {
     fn from(value: VMError) -> Self {
-        Self::VirtualmachineError(value)
+        Self::VirtualmachineError {
+            error: value,
+            source: None,
+        }
+    }
+}
+
+/// Whole numbers without a decimal point and other values in fixed notation,
+/// unless they are so large or small that scientific notation is shorter.
+fn format_result(value: f64) -> String {
+    let magnitude = value.abs();
+    if value == 0.0 || !value.is_finite() || (1e-6..1e15).contains(&magnitude) {
+        format!("{}", value)
+    } else {
+        format!("{:e}", value)
     }
 }
 
+/// Evaluate the expressions of a file one after the other, carrying `ans`
+/// forward, and return the last result or the first error.
+fn run_file(src: &[u8]) -> Result<f64, ApplicationError> {
+    run_file_timed(src).map(|(value, _, _)| value)
+}
+
+/// Like `run_file`, also returning the total time spent compiling and the
+/// total time spent interpreting.
+fn run_file_timed(src: &[u8]) -> Result<(f64, Duration, Duration), ApplicationError> {
+    let (mut compile_time, mut eval_time) = (Duration::ZERO, Duration::ZERO);
+    let mut last = Err(ApplicationError::from(CompilerError::EmptyExpression).with_source(src));
+    for res in run_script_timed(src) {
+        let (value, compiled, evaluated) = res?;
+        compile_time += compiled;
+        eval_time += evaluated;
+        last = Ok(value);
+    }
+    last.map(|value| (value, compile_time, eval_time))
+}
+
+/// Read a whole file from `r` and evaluate it like file mode does, returning
+/// the last result. The source does not need to be valid UTF-8, as the lexer
+/// works on bytes.
+pub fn eval_reader<R: Read>(mut r: R) -> Result<f64, ApplicationError> {
+    let mut src = Vec::new();
+    r.read_to_end(&mut src)
+        .map_err(|e| ApplicationError::Read(e.to_string()))?;
+    run_file(&src)
+}
+
+/// The result of each line of a script, with the time spent compiling and
+/// interpreting it, evaluated as the iterator is advanced. Every newline ends a
+/// statement, so an expression cannot be wrapped across lines. The compiler
+/// takes care of `;` within a line and of `#` comments; lines that are blank or
+/// only hold a comment are skipped.
+fn run_script_timed(
+    src: &[u8],
+) -> impl Iterator<Item = Result<(f64, Duration, Duration), ApplicationError>> + '_ {
+    let mut compiler = Compiler::default();
+    let mut vm = VirtualMachine::default();
+    src.split(|&c| c == b'\n').filter_map(move |line| {
+        let line = line.trim_ascii();
+        let res = timed_eval(&mut compiler, &mut vm, line);
+        compiler.reset();
+        if let Err(ApplicationError::CompileError {
+            error: CompilerError::EmptyExpression,
+            ..
+        }) = res
+        {
+            return None;
+        }
+        let res = res.map_err(|e| e.with_source(line));
+        vm.reset(res.as_ref().ok().map(|&(value, _, _)| value));
+        Some(res)
+    })
+}
+
+/// Compile and interpret `src`, returning the result with the time spent
+/// compiling and the time spent interpreting.
+fn timed_eval(
+    compiler: &mut Compiler,
+    vm: &mut VirtualMachine,
+    src: &[u8],
+) -> Result<(f64, Duration, Duration), ApplicationError> {
+    let start = Instant::now();
+    let mut lexer = Lexer::new(src).with_comments(true);
+    compiler.compile(&mut lexer)?;
+    let compiled = Instant::now();
+    let res = vm.interpret(compiler.opcodes())?;
+    Ok((res, compiled - start, compiled.elapsed()))
+}
+
 #[cfg(not(feature = "gui"))]
 mod terminal {
-    use super::ApplicationError;
-    use std::io::{self, Write};
+    use super::{
+        format_result, run_file, run_file_timed, run_script_timed, timed_eval, ApplicationError,
+        CompilerError,
+    };
+    use std::{
+        io::{self, BufRead, Write},
+        time::Duration,
+    };
 
     use crate::{
-        compiler::{Compile, Compiler},
-        lexer::Lexer,
+        ast::{self, Expr},
+        compiler::Compiler,
+        lexer::{Error as LexerError, FuncType, Lexer, Scan},
         vm::VirtualMachine,
     };
 
-    fn run_file(src: &[u8]) -> Result<f64, ApplicationError> {
+    /// Evaluate each expression of a script like the repl does, carrying `ans`
+    /// forward.
+    fn run_script(src: &[u8]) -> impl Iterator<Item = Result<f64, ApplicationError>> + '_ {
+        run_script_timed(src).map(|res| res.map(|(value, _, _)| value))
+    }
+
+    fn read_file(path: &str) -> Result<Vec<u8>, ApplicationError> {
+        std::fs::read(path).map_err(|e| ApplicationError::Read(format!("{}: {}", path, e)))
+    }
+
+    /// The tree of a file, its lines being statements like in file mode.
+    fn file_ast(src: &[u8]) -> Result<Expr, ApplicationError> {
+        let mut statements = Vec::new();
+        for line in src.split(|&c| c == b'\n') {
+            match ast::parse(line) {
+                Ok(Expr::Sequence(exprs)) => statements.extend(exprs),
+                Ok(expr) => statements.push(expr),
+                Err(CompilerError::EmptyExpression) => {}
+                Err(e) => return Err(ApplicationError::from(e).with_source(line)),
+            }
+        }
+        if statements.is_empty() {
+            return Err(ApplicationError::from(CompilerError::EmptyExpression).with_source(src));
+        }
+        Ok(ast::sequence(statements))
+    }
+
+    /// Every token of `src` on its own line, after the byte offset it starts at.
+    fn token_dump(src: &[u8]) -> Result<String, ApplicationError> {
         let mut lexer = Lexer::new(src);
-        let mut compiler = Compiler::default();
-        compiler.compile(&mut lexer)?;
-        let mut vm = VirtualMachine::default();
-        vm.interpret(compiler.opcodes()).map_err(|e| e.into())
+        let mut lines = Vec::new();
+        loop {
+            match lexer.scan() {
+                Ok(token) => {
+                    let offset = lexer.span().map_or(0, |span| span.start);
+                    lines.push(format!("{} {}", offset, String::from(token)));
+                }
+                Err(LexerError::Eof) => return Ok(lines.join("\n")),
+                Err(e) => {
+                    return Err(ApplicationError::from(CompilerError::from(e)).with_source(src))
+                }
+            }
+        }
     }
 
-    fn run_repl() -> ! {
-        let mut input = String::new();
+    /// The argument of the repl command `name`, `None` for any other line,
+    /// including ones where something is glued to the command name.
+    fn command_arg<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+        let arg = line.trim().strip_prefix(name)?;
+        if !arg.is_empty() && !arg.starts_with(char::is_whitespace) {
+            return None;
+        }
+        Some(arg.trim())
+    }
+
+    /// The path of a `:load path` repl command, `None` for any other line.
+    fn load_command(line: &str) -> Option<&str> {
+        command_arg(line, ":load")
+    }
+
+    /// Read and evaluate a file like `run_file`, without giving up on a read error.
+    fn load_file(path: &str) -> Result<f64, ApplicationError> {
+        run_file(&read_file(path)?)
+    }
+
+    /// The contents of the file named after `flag` on the command line. A
+    /// missing path or a read error is printed, leaving the exit code to return.
+    fn read_path_arg(flag: &str) -> Result<Vec<u8>, std::process::ExitCode> {
+        std::env::args()
+            .nth(2)
+            .ok_or_else(|| ApplicationError::Read(format!("missing file path after {}", flag)))
+            .and_then(|path| read_file(&path))
+            .map_err(|e| {
+                eprintln!("{}", e);
+                std::process::ExitCode::FAILURE
+            })
+    }
+
+    fn format_timing(compile_time: Duration, eval_time: Duration) -> String {
+        format!(
+            "compiled in {:?}, evaluated in {:?}",
+            compile_time, eval_time
+        )
+    }
+
+    /// Help for the function called `name`, or for every function if `name` is empty.
+    fn help(name: &str) -> String {
+        if name.is_empty() {
+            return FuncType::all()
+                .map(|f| f.description())
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        match FuncType::all().find(|f| f.name() == name) {
+            Some(f) => f.description().to_owned(),
+            None => format!("Unknown function {}", name),
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Stats {
+        count: usize,
+        sum: f64,
+        mean: f64,
+    }
+
+    fn stats(values: &[f64]) -> Option<Stats> {
+        if values.is_empty() {
+            return None;
+        }
+        let sum: f64 = values.iter().sum();
+        Some(Stats {
+            count: values.len(),
+            sum,
+            mean: sum / values.len() as f64,
+        })
+    }
+
+    /// The strings the repl decorates its output with.
+    struct ReplConfig {
+        prompt: String,
+        result_prefix: String,
+    }
+
+    impl Default for ReplConfig {
+        fn default() -> Self {
+            Self {
+                prompt: ">> ".into(),
+                result_prefix: "$ ".into(),
+            }
+        }
+    }
+
+    impl ReplConfig {
+        /// No prompt and bare results, for driving the repl from another program.
+        fn quiet() -> Self {
+            Self {
+                prompt: String::new(),
+                result_prefix: String::new(),
+            }
+        }
+
+        /// `CALC_QUIET` selects `quiet`, otherwise `CALC_PROMPT` replaces the prompt.
+        fn from_env() -> Self {
+            if std::env::var_os("CALC_QUIET").is_some() {
+                return Self::quiet();
+            }
+            match std::env::var("CALC_PROMPT") {
+                Ok(prompt) => Self {
+                    prompt,
+                    ..Self::default()
+                },
+                Err(_) => Self::default(),
+            }
+        }
+    }
+
+    fn run_repl() -> std::process::ExitCode {
+        // a different `rand()` sequence on every session
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let res = repl(
+            &ReplConfig::from_env(),
+            VirtualMachine::new_seeded(seed),
+            io::stdin().lock(),
+            &mut io::stdout(),
+            &mut io::stderr(),
+        );
+        match res {
+            Ok(()) => std::process::ExitCode::SUCCESS,
+            Err(_) => std::process::ExitCode::FAILURE,
+        }
+    }
+
+    /// Read and evaluate lines from `input` until it ends.
+    fn repl(
+        config: &ReplConfig,
+        mut vm: VirtualMachine,
+        mut input: impl BufRead,
+        out: &mut impl Write,
+        err: &mut impl Write,
+    ) -> io::Result<()> {
+        let mut line = String::new();
         let mut compiler = Compiler::default();
-        let mut vm = VirtualMachine::default();
+        let mut results = Vec::new();
         loop {
-            print!(">> ");
-            io::stdout().flush().unwrap();
-            input.clear();
-            if io::stdin().read_line(&mut input).is_err() {
+            write!(out, "{}", config.prompt)?;
+            out.flush()?;
+            line.clear();
+            match input.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(_) => continue,
+            }
+            if line == "\n" || line == "\r\n" {
                 continue;
             }
-            if input == "\n" || input == "\r\n" {
+            if line.trim() == ":stats" {
+                match stats(&results) {
+                    Some(Stats { count, sum, mean }) => {
+                        writeln!(out, "count: {}, sum: {}, mean: {}", count, sum, mean)?
+                    }
+                    None => writeln!(out, "No results yet")?,
+                }
                 continue;
             }
-            let bytes = input.as_bytes();
-            let mut lexer = Lexer::new(bytes);
-            if let Err(e) = compiler.compile(&mut lexer) {
-                eprintln!("Compiler error: {}", e);
-                compiler.reset();
+            if let Some(name) = command_arg(&line, ":help") {
+                writeln!(out, "{}", help(name))?;
                 continue;
             }
-            let ans = match vm.interpret(compiler.opcodes()) {
-                Ok(value) => {
-                    println!("$ {}", value);
+            if let Some(src) = command_arg(&line, ":tokens") {
+                match token_dump(src.as_bytes()) {
+                    Ok(dump) => writeln!(out, "{}", dump)?,
+                    Err(e) => writeln!(err, "{}", e)?,
+                }
+                continue;
+            }
+            if let Some(path) = load_command(&line) {
+                match load_file(path) {
+                    Ok(value) => {
+                        writeln!(out, "{}{}", config.result_prefix, format_result(value))?;
+                        results.push(value);
+                        vm.reset(Some(value));
+                    }
+                    Err(e) => writeln!(err, "{}", e)?,
+                }
+                continue;
+            }
+            let (src, time) = match command_arg(&line, ":time") {
+                Some(expr) => (expr, true),
+                None => (line.as_str(), false),
+            };
+            let ans = match timed_eval(&mut compiler, &mut vm, src.as_bytes()) {
+                Ok((value, compile_time, eval_time)) => {
+                    writeln!(out, "{}{}", config.result_prefix, format_result(value))?;
+                    if time {
+                        writeln!(out, "{}", format_timing(compile_time, eval_time))?;
+                    }
+                    results.push(value);
                     Some(value)
                 }
+                Err(e @ ApplicationError::CompileError { .. }) => {
+                    writeln!(err, "{}", e)?;
+                    compiler.reset();
+                    continue;
+                }
                 Err(e) => {
-                    eprintln!("Virtual machine error: {}", e);
+                    writeln!(err, "{}", e)?;
                     None
                 }
             };
@@ -83,12 +430,48 @@ mod terminal {
 
     pub fn run() -> std::process::ExitCode {
         match std::env::args().nth(1) {
-            Some(src_path) => {
-                let src =
-                    std::fs::read(src_path).unwrap_or_else(|e| panic!("Cannot read file {}", e));
-                match run_file(&src) {
-                    Ok(res) => {
-                        println!("Result of computation: {}", res);
+            Some(flag) if flag == "--script" => {
+                let src = match read_path_arg(&flag) {
+                    Ok(src) => src,
+                    Err(exit_code) => return exit_code,
+                };
+                let mut exit_code = std::process::ExitCode::SUCCESS;
+                for res in run_script(&src) {
+                    match res {
+                        Ok(value) => println!("{}", format_result(value)),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            exit_code = std::process::ExitCode::FAILURE;
+                        }
+                    }
+                }
+                exit_code
+            }
+            Some(flag) if flag == "--time" => {
+                let src = match read_path_arg(&flag) {
+                    Ok(src) => src,
+                    Err(exit_code) => return exit_code,
+                };
+                match run_file_timed(&src) {
+                    Ok((res, compile_time, eval_time)) => {
+                        println!("Result of computation: {}", format_result(res));
+                        println!("{}", format_timing(compile_time, eval_time));
+                        std::process::ExitCode::SUCCESS
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::ExitCode::FAILURE
+                    }
+                }
+            }
+            Some(flag) if flag == "--tokens" => {
+                let src = match read_path_arg(&flag) {
+                    Ok(src) => src,
+                    Err(exit_code) => return exit_code,
+                };
+                match token_dump(&src) {
+                    Ok(dump) => {
+                        println!("{}", dump);
                         std::process::ExitCode::SUCCESS
                     }
                     Err(e) => {
@@ -97,9 +480,228 @@ mod terminal {
                     }
                 }
             }
+            Some(flag) if flag == "--ast" => {
+                let src = match read_path_arg(&flag) {
+                    Ok(src) => src,
+                    Err(exit_code) => return exit_code,
+                };
+                match file_ast(&src) {
+                    Ok(tree) => {
+                        println!("{}", tree.pretty());
+                        std::process::ExitCode::SUCCESS
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::ExitCode::FAILURE
+                    }
+                }
+            }
+            Some(src_path) => match read_file(&src_path).and_then(|src| run_file(&src)) {
+                Ok(res) => {
+                    println!("Result of computation: {}", format_result(res));
+                    std::process::ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::ExitCode::FAILURE
+                }
+            },
             None => run_repl(),
         }
     }
+
+    #[cfg(test)]
+    mod terminal_tests {
+        use super::*;
+
+        fn run_repl_with(config: ReplConfig, input: &str) -> (String, String) {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let vm = VirtualMachine::default();
+            repl(&config, vm, input.as_bytes(), &mut out, &mut err).unwrap();
+            (
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        }
+
+        #[test]
+        fn test_run_script() {
+            let script = b"1 + 2\n\n# double it\nans * 2\r\n  ans - 1  \n";
+            let results: Vec<_> = run_script(script)
+                .map(|r| r.map_err(|e| e.to_string()))
+                .collect();
+            assert_eq!(results, vec![Ok(3.0), Ok(6.0), Ok(5.0)]);
+        }
+
+        #[test]
+        fn test_run_script_comments_and_semicolons() {
+            let results: Vec<_> = run_script(b"1 + 2 # note\n  # only a comment\n2; ans * 5")
+                .map(|r| r.map_err(|e| e.to_string()))
+                .collect();
+            assert_eq!(results, vec![Ok(3.0), Ok(10.0)]);
+        }
+
+        #[test]
+        fn test_run_script_error_resets_ans() {
+            let results: Vec<_> = run_script(b"2\n1 / 0\nans").collect();
+            assert_eq!(results.len(), 3);
+            assert_eq!(results[0].as_ref().ok(), Some(&2.0));
+            assert!(results[1].is_err());
+            assert!(results[2].is_err());
+        }
+
+        #[test]
+        fn test_token_dump() {
+            assert_eq!(
+                token_dump(b"sin(2)+1").unwrap(),
+                "0 sin\n3 (\n4 2\n5 )\n6 +\n7 1"
+            );
+            assert_eq!(
+                token_dump(b"1 @").unwrap_err().to_string(),
+                "Compiler error in \"1 @\": invalid character '@'"
+            );
+        }
+
+        #[test]
+        fn test_file_ast() {
+            assert_eq!(
+                file_ast(b"2 * 3\n\n# comment\nans + 10%; ans")
+                    .unwrap()
+                    .pretty(),
+                ";\n  *\n    2\n    3\n  + % of\n    ans\n    10\n  ans"
+            );
+            assert_eq!(file_ast(b"1 + 2\n").unwrap().pretty(), "+\n  1\n  2");
+            assert_eq!(
+                file_ast(b"1\n2 +\n").unwrap_err().to_string(),
+                "Compiler error in \"2 +\": unexpected token '+' at the end"
+            );
+        }
+
+        #[test]
+        fn test_repl_tokens() {
+            let (out, err) = run_repl_with(ReplConfig::quiet(), ":tokens 1+2\n:tokensfoo\n");
+            assert_eq!(out, "0 1\n1 +\n2 2\n");
+            assert_eq!(err, "Compiler error: invalid character ':'\n");
+        }
+
+        #[test]
+        fn test_repl_time() {
+            let (out, err) = run_repl_with(ReplConfig::quiet(), ":time 2 * 3\n:timex\n");
+            let mut lines = out.lines();
+            assert_eq!(lines.next(), Some("6"));
+            assert!(lines
+                .next()
+                .is_some_and(|line| line.starts_with("compiled in ")));
+            assert_eq!(lines.next(), None);
+            assert_eq!(err, "Compiler error: invalid character ':'\n");
+        }
+
+        #[test]
+        fn test_repl_custom_prompt() {
+            let config = ReplConfig {
+                prompt: "calc> ".into(),
+                ..ReplConfig::default()
+            };
+            let (out, err) = run_repl_with(config, "1 + 1\nans * 3\n:stats\n");
+            assert_eq!(
+                out,
+                "calc> $ 2\ncalc> $ 6\ncalc> count: 2, sum: 8, mean: 4\ncalc> "
+            );
+            assert!(err.is_empty());
+        }
+
+        #[test]
+        fn test_repl_semicolons() {
+            let (out, err) = run_repl_with(ReplConfig::quiet(), "1 + 1; ans * 3; ans - 1\n");
+            assert_eq!(out, "5\n");
+            assert!(err.is_empty());
+        }
+
+        #[test]
+        fn test_load_command() {
+            assert_eq!(load_command(":load a.calc\n"), Some("a.calc"));
+            assert_eq!(
+                load_command("  :load   dir/b c.calc "),
+                Some("dir/b c.calc")
+            );
+            assert_eq!(load_command(":load"), Some(""));
+            assert_eq!(load_command(":loader"), None);
+            assert_eq!(load_command("1 + 2"), None);
+        }
+
+        #[test]
+        fn test_repl_load() {
+            let path = std::env::temp_dir().join("vm_calculator_test_repl_load.calc");
+            std::fs::write(&path, "2 * 3\nans + 1\n").unwrap();
+            let input = format!(
+                ":load {}\nans * 2\n:load missing.calc\nans\n",
+                path.display()
+            );
+            let (out, err) = run_repl_with(ReplConfig::quiet(), &input);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(out, "7\n14\n14\n");
+            assert!(err.starts_with("Read error: missing.calc: "));
+        }
+
+        #[test]
+        fn test_repl_quiet() {
+            let (out, err) = run_repl_with(ReplConfig::quiet(), "1 + 1\n1 +\n\n2 * 3\n");
+            assert_eq!(out, "2\n6\n");
+            assert!(!err.is_empty());
+        }
+
+        #[test]
+        fn test_timed_eval() {
+            let mut compiler = Compiler::default();
+            let mut vm = VirtualMachine::default();
+            let res = timed_eval(&mut compiler, &mut vm, b"2+2");
+            assert!(matches!(res, Ok((r, _, _)) if r == 4.0));
+            compiler.reset();
+            vm.reset(None);
+            let res = timed_eval(&mut compiler, &mut vm, b"(2");
+            assert!(matches!(res, Err(ApplicationError::CompileError { .. })));
+        }
+
+        #[test]
+        fn test_format_timing() {
+            assert_eq!(
+                format_timing(Duration::from_micros(12), Duration::from_micros(3)),
+                "compiled in 12µs, evaluated in 3µs"
+            );
+        }
+
+        #[test]
+        fn test_repl_help() {
+            let (out, err) = run_repl_with(ReplConfig::quiet(), ":help  sqrt\n:helpsqrt\n");
+            assert_eq!(out, "sqrt(x): x ≥ 0\n");
+            assert_eq!(err, "Compiler error: invalid character ':'\n");
+        }
+
+        #[test]
+        fn test_help() {
+            assert_eq!(help("sqrt"), "sqrt(x): x ≥ 0");
+            assert_eq!(help("foo"), "Unknown function foo");
+            assert_eq!(help("").lines().count(), FuncType::all().count());
+        }
+
+        #[test]
+        fn test_stats_empty() {
+            assert_eq!(stats(&[]), None);
+        }
+
+        #[test]
+        fn test_stats() {
+            let res = stats(&[1.0, 2.0, 6.0]);
+            assert_eq!(
+                res,
+                Some(Stats {
+                    count: 3,
+                    sum: 9.0,
+                    mean: 3.0
+                })
+            );
+        }
+    }
 }
 
 #[cfg(not(feature = "gui"))]
@@ -112,60 +714,87 @@ mod gui {
 
     use crate::{
         compiler::{Compile, Compiler},
-        lexer::Lexer,
-        vm::VirtualMachine,
+        lexer::{FuncType, Lexer},
+        vm::{AngleMode, VirtualMachine},
     };
 
-    use super::ApplicationError;
+    use super::{format_result, ApplicationError};
 
     const MAX_HISTORY_LEN: usize = 10;
 
-    struct App {
-        expressions: VecDeque<(String, Option<f64>)>,
-        expression_index: usize,
+    pub struct App {
+        history: VecDeque<(String, f64)>,
+        max_history_len: usize,
+        expression: String,
+        ans: Option<f64>,
         result: String,
+        result_is_error: bool,
         compiler: Compiler,
         vm: VirtualMachine,
     }
 
     impl Default for App {
         fn default() -> Self {
-            let mut expressions = VecDeque::new();
-            expressions.push_back(("".to_owned(), None));
-            Self {
-                expressions,
-                expression_index: 0,
-                result: "".to_owned(),
-                compiler: Compiler::default(),
-                vm: VirtualMachine::default(),
-            }
+            Self::with_history_len(MAX_HISTORY_LEN)
         }
     }
 
     impl eframe::App for App {
         fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
             egui::CentralPanel::default().show(ctx, |ui| {
-                ui.heading("Calculator");
-                ui.label(&self.expressions[self.index()].0);
-                ui.label(&self.result);
+                ui.heading(format!(
+                    "Calculator ({})",
+                    angle_mode_label(self.vm.angle_mode())
+                ));
+                ui.label(&self.expression);
+                ui.horizontal(|ui| {
+                    ui.label(&self.result);
+                    let copyable = self.copyable_result();
+                    if ui
+                        .add_enabled(copyable.is_some(), egui::Button::new("Copy"))
+                        .clicked()
+                    {
+                        if let Some(text) = copyable {
+                            ui.output_mut(|o| o.copied_text = text.to_owned());
+                        }
+                    }
+                });
                 self.buttons(ui);
+                ui.separator();
+                self.history(ui);
             });
         }
     }
 
-    impl App {
-        fn index(&self) -> usize {
-            self.expressions.len() - self.expression_index - 1
+    fn angle_mode_label(mode: AngleMode) -> &'static str {
+        match mode {
+            AngleMode::Radians => "RAD",
+            AngleMode::Degrees => "DEG",
         }
+    }
 
-        fn is_current_expression(&self) -> bool {
-            self.expression_index == 0
+    fn format_history_entry(expression: &str, result: f64) -> String {
+        format!("{} = {}", expression, format_result(result))
+    }
+
+    impl App {
+        /// Create an app keeping at most `max_history_len` evaluated expressions.
+        pub fn with_history_len(max_history_len: usize) -> Self {
+            Self {
+                history: VecDeque::new(),
+                max_history_len,
+                expression: "".to_owned(),
+                ans: None,
+                result: "".to_owned(),
+                result_is_error: false,
+                compiler: Compiler::default(),
+                vm: VirtualMachine::default(),
+            }
         }
 
         fn solve(&mut self) {
-            let (s, ans) = &self.expressions[self.index()];
-            let mut lexer = Lexer::new(s.as_bytes());
-            self.vm.reset(*ans);
+            let mut lexer = Lexer::new(self.expression.as_bytes());
+            self.vm.reset(self.ans);
             let res = self
                 .compiler
                 .compile(&mut lexer)
@@ -174,28 +803,71 @@ mod gui {
                     self.vm
                         .interpret(self.compiler.opcodes())
                         .map_err(|e| e.into())
-                });
+                })
+                .map_err(|e| e.with_source(self.expression.as_bytes()));
             match res {
                 Ok(r) => {
-                    self.result = format!("{:+e}", r);
-                    if self.is_current_expression() {
-                        self.expressions.push_back(("".to_owned(), Some(r)));
-                    }
-                    if self.expressions.len() >= MAX_HISTORY_LEN {
-                        self.expressions.pop_front();
+                    self.result = format_result(r);
+                    self.result_is_error = false;
+                    self.ans = Some(r);
+                    self.history
+                        .push_back((std::mem::take(&mut self.expression), r));
+                    while self.history.len() > self.max_history_len {
+                        self.history.pop_front();
                     }
                     self.compiler.reset();
                 }
                 Err(e) => {
                     self.result = e.to_string();
-                    if let Some((s, _)) = self.expressions.back_mut() {
-                        s.clear();
-                    }
+                    self.result_is_error = true;
+                    self.expression.clear();
                     self.compiler.reset();
                 }
             };
         }
 
+        /// The mode lives in the machine, which `solve` does not rebuild, so it
+        /// holds until toggled again.
+        fn toggle_angle_mode(&mut self) {
+            self.vm.set_angle_mode(match self.vm.angle_mode() {
+                AngleMode::Radians => AngleMode::Degrees,
+                AngleMode::Degrees => AngleMode::Radians,
+            });
+        }
+
+        fn history(&mut self, ui: &mut egui::Ui) {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let mut selected = None;
+                for (i, (expression, result)) in self.history.iter().enumerate().rev() {
+                    let entry = format_history_entry(expression, *result);
+                    if ui.selectable_label(false, entry).clicked() {
+                        selected = Some(i);
+                    }
+                }
+                if let Some(i) = selected {
+                    self.select_history(i);
+                }
+            });
+        }
+
+        /// The result text as displayed, if it can be copied to the clipboard.
+        /// Error messages are not copyable, so the Copy button is disabled for them.
+        fn copyable_result(&self) -> Option<&str> {
+            if self.result_is_error || self.result.is_empty() {
+                None
+            } else {
+                Some(&self.result)
+            }
+        }
+
+        /// Copy the history entry at `index` (0 is the oldest) into the current expression.
+        /// Indexes past the newest entry are clamped to it.
+        fn select_history(&mut self, index: usize) {
+            if let Some(last) = self.history.len().checked_sub(1) {
+                self.expression.clone_from(&self.history[index.min(last)].0);
+            }
+        }
+
         fn draw_number_row(&mut self, ui: &mut egui::Ui, nums: [&'static str; 3]) {
             for num in nums {
                 self.draw_small_btn(ui, num, None, |s| s.push_str(num));
@@ -228,10 +900,8 @@ mod gui {
             } else {
                 btn
             };
-            if ui.add(btn).clicked() && self.is_current_expression() {
-                if let Some((s, _)) = self.expressions.back_mut() {
-                    btn_cb(s);
-                }
+            if ui.add(btn).clicked() {
+                btn_cb(&mut self.expression);
             }
         }
 
@@ -280,20 +950,15 @@ mod gui {
                             self.solve();
                         }
                     });
-                    self.draw_function(ui, "cos");
-                    self.draw_function(ui, "sqrt");
                 });
                 ui.horizontal(|ui| {
                     self.draw_number_row(ui, ["1", "2", "3"]);
                     self.draw_small_single_char_btn(ui, "+");
-                    self.draw_function(ui, "sin");
-                    self.draw_function(ui, "log");
                 });
 
                 ui.horizontal(|ui| {
                     self.draw_number_row(ui, ["4", "5", "6"]);
                     self.draw_small_single_char_btn(ui, "-");
-                    self.draw_function(ui, "pow");
                     self.draw_big_btn(ui, "10^x", None, |s| {
                         s.push('e');
                     });
@@ -313,25 +978,27 @@ mod gui {
                     self.draw_small_single_char_btn(ui, ".");
                     self.draw_small_single_char_btn(ui, ",");
                     self.draw_small_single_char_btn(ui, "/");
-                    if ui.add(large_btn("prev")).clicked() {
-                        let idx = self.expression_index + 1;
-                        if self.expressions.len() > idx {
-                            self.expression_index = idx;
-                        }
-                    }
-                    if ui.add(large_btn("next")).clicked() && !self.is_current_expression() {
-                        self.expression_index -= 1;
+                    if ui.add(large_btn("DEG/RAD")).clicked() {
+                        self.toggle_angle_mode();
                     }
                 });
+                let functions: Vec<_> = FuncType::all().collect();
+                for row in functions.chunks(FUNCTIONS_PER_ROW) {
+                    ui.horizontal(|ui| {
+                        for f in row {
+                            self.draw_function(ui, f.name());
+                        }
+                    });
+                }
             });
         }
     }
 
-    fn single_char_btn(n: &str) -> egui::Button {
+    fn single_char_btn(n: &str) -> egui::Button<'_> {
         egui::Button::new(n).min_size(egui::Vec2::new(BTN_WIDTH, BTN_HEGHT))
     }
 
-    fn large_btn(n: &str) -> egui::Button {
+    fn large_btn(n: &str) -> egui::Button<'_> {
         egui::Button::new(n).min_size(egui::Vec2::new(BTN_LARGE_WIDTH, BTN_HEGHT))
     }
 
@@ -339,7 +1006,8 @@ mod gui {
     const BTN_WIDTH: f32 = 20.0;
     const BTN_LARGE_WIDTH: f32 = 2. * BTN_WIDTH;
     const W_WIDTH: f32 = 8. * BTN_WIDTH + 20.;
-    const W_HEIGHT: f32 = 200.0;
+    const W_HEIGHT: f32 = 400.0;
+    const FUNCTIONS_PER_ROW: usize = 4;
 
     pub fn run() -> std::process::ExitCode {
         let options = eframe::NativeOptions {
@@ -354,7 +1022,179 @@ mod gui {
             }
         }
     }
+
+    #[cfg(test)]
+    mod gui_tests {
+        use super::*;
+
+        #[test]
+        fn test_format_history_entry() {
+            assert_eq!(format_history_entry("1 + 2", 3.0), "1 + 2 = 3");
+            assert_eq!(format_history_entry("-ans", -0.5), "-ans = -0.5");
+        }
+
+        #[test]
+        fn test_error_shows_expression() {
+            let mut app = App {
+                expression: "1 / 0".to_owned(),
+                ..Default::default()
+            };
+            app.solve();
+            assert!(app.result_is_error);
+            assert!(app.result.contains("\"1 / 0\""));
+        }
+
+        #[test]
+        fn test_history_trimmed_to_configured_len() {
+            let mut app = App::with_history_len(3);
+            for i in 0..5 {
+                app.expression = format!("{} + 1", i);
+                app.solve();
+            }
+            assert_eq!(app.history.len(), 3);
+            assert_eq!(app.history[0], ("2 + 1".to_owned(), 3.0));
+            assert_eq!(app.history[2], ("4 + 1".to_owned(), 5.0));
+        }
+
+        #[test]
+        fn test_select_oldest_after_trim() {
+            let mut app = App::default();
+            for i in 0..MAX_HISTORY_LEN + 3 {
+                app.expression = format!("{}", i);
+                app.solve();
+            }
+            assert_eq!(app.history.len(), MAX_HISTORY_LEN);
+            app.select_history(0);
+            assert_eq!(app.expression, "3");
+            app.select_history(MAX_HISTORY_LEN + 3);
+            assert_eq!(app.expression, format!("{}", MAX_HISTORY_LEN + 2));
+        }
+
+        #[test]
+        fn test_copyable_result() {
+            let mut app = App::default();
+            assert_eq!(app.copyable_result(), None);
+            app.expression = "1 + 2".to_owned();
+            app.solve();
+            assert_eq!(app.copyable_result(), Some("3"));
+            app.expression = "1 / 0".to_owned();
+            app.solve();
+            assert_eq!(app.copyable_result(), None);
+        }
+
+        #[test]
+        fn test_angle_mode_toggle() {
+            let mut app = App::default();
+            assert_eq!(angle_mode_label(app.vm.angle_mode()), "RAD");
+            app.toggle_angle_mode();
+            assert_eq!(angle_mode_label(app.vm.angle_mode()), "DEG");
+            app.expression = "cos(180)".to_owned();
+            app.solve();
+            assert_eq!(app.result, "-1");
+            assert_eq!(app.vm.angle_mode(), AngleMode::Degrees);
+        }
+
+        #[test]
+        fn test_select_empty_history() {
+            let mut app = App::default();
+            app.select_history(0);
+            assert_eq!(app.expression, "");
+        }
+    }
 }
 
 #[cfg(feature = "gui")]
 pub use gui::*;
+
+#[cfg(test)]
+mod app_tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_run_file_error_shows_source() {
+        let err = run_file(b"1 + @\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Compiler error in \"1 + @\": invalid character '@'"
+        );
+        let err = run_file(b"1 / 0").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Virtual machine error in \"1 / 0\": division by zero"
+        );
+    }
+
+    #[test]
+    fn test_run_file_chains_ans() {
+        assert!(matches!(run_file(b"2 * 3\nans + 1\n"), Ok(r) if r == 7.0));
+        assert!(matches!(run_file(b"2; ans * 5"), Ok(r) if r == 10.0));
+        assert!(matches!(run_file(b"# only; 1\n3"), Ok(r) if r == 3.0));
+        let err = run_file(b"1 / 0\nans + 1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Virtual machine error in \"1 / 0\": division by zero"
+        );
+        assert!(matches!(
+            run_file(b"\n"),
+            Err(ApplicationError::CompileError {
+                error: CompilerError::EmptyExpression,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_run_file_newline_ends_statement() {
+        // the expression is not continued on the next line
+        let err = run_file(b"(1 +\n2)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Compiler error in \"(1 +\": unexpected token '+' at the end"
+        );
+        // lines are evaluated as the results are taken
+        let mut results = run_script_timed(b"1 / 0\n2\n");
+        assert!(matches!(
+            results.next(),
+            Some(Err(ApplicationError::VirtualmachineError { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_eval_reader() {
+        assert!(matches!(
+            eval_reader(io::Cursor::new(b"3*4")),
+            Ok(r) if r == 12.0
+        ));
+        let err = eval_reader(io::Cursor::new(b"1 + \xff")).unwrap_err();
+        assert!(matches!(err, ApplicationError::CompileError { .. }));
+
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken pipe"))
+            }
+        }
+        let err = eval_reader(FailingReader).unwrap_err();
+        assert_eq!(err.to_string(), "Read error: broken pipe");
+    }
+
+    #[test]
+    fn test_run_file_timed_chains_ans() {
+        let (value, _, _) = run_file_timed(b"2*3\nans+1").unwrap();
+        assert_eq!(value, 7.0);
+        assert!(run_file_timed(b"2\n1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_format_result() {
+        assert_eq!(format_result(4.0), "4");
+        assert_eq!(format_result(-4.0), "-4");
+        assert_eq!(format_result(0.5), "0.5");
+        assert_eq!(format_result(0.0), "0");
+        assert_eq!(format_result(1e-9), "1e-9");
+        assert_eq!(format_result(1e20), "1e20");
+        assert_eq!(format_result(123456.75), "123456.75");
+        assert_eq!(format_result(f64::INFINITY), "inf");
+    }
+}
@@ -0,0 +1,437 @@
+//! An expression tree, built by a parser separate from the single pass compiler
+//! but following the same precedence rules. It only serves to show how an
+//! expression is grouped: the bytecode never goes through it.
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    compiler::{is_binary_operator, Error},
+    lexer::{
+        normalize_number, split_angle_suffix, AngleSuffix, Error as LexerError, Lexer, Priority,
+        Scan, Span, Token,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(f64),
+    /// `ans` and the named constants.
+    Name(String),
+    /// `-x`, or `x%` with `op` `%`.
+    Unary {
+        op: String,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: String,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `lhs + rhs%` or `lhs - rhs%`, adding or subtracting `rhs` percent of `lhs`.
+    PercentOf {
+        op: String,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// Statements separated by `;`, each one the `ans` of the next.
+    Sequence(Vec<Expr>),
+    /// A function call, including `|x|` as `abs` and `ans(n)` as `ans`.
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+}
+
+impl Expr {
+    /// One node per line, children indented below their parent.
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, depth: usize) {
+        for _ in 0..depth {
+            out.push_str("  ");
+        }
+        match self {
+            Self::Num(n) => out.push_str(&n.to_string()),
+            Self::Name(name) => out.push_str(name),
+            Self::Unary { op, operand } => {
+                out.push_str(op);
+                out.push('\n');
+                operand.write_pretty(out, depth + 1);
+            }
+            Self::Binary { op, lhs, rhs } => {
+                out.push_str(op);
+                out.push('\n');
+                lhs.write_pretty(out, depth + 1);
+                out.push('\n');
+                rhs.write_pretty(out, depth + 1);
+            }
+            Self::PercentOf { op, lhs, rhs } => {
+                out.push_str(op);
+                out.push_str(" % of");
+                out.push('\n');
+                lhs.write_pretty(out, depth + 1);
+                out.push('\n');
+                rhs.write_pretty(out, depth + 1);
+            }
+            Self::Sequence(statements) => {
+                out.push(';');
+                for statement in statements {
+                    out.push('\n');
+                    statement.write_pretty(out, depth + 1);
+                }
+            }
+            Self::Call { name, args } => {
+                out.push_str(name);
+                out.push_str("()");
+                for arg in args {
+                    out.push('\n');
+                    arg.write_pretty(out, depth + 1);
+                }
+            }
+        }
+    }
+}
+
+/// Parse the whole of `src`, which may hold several statements separated by `;`
+/// and `#` comments. Arities are not checked, that is left to the compiler.
+pub fn parse(src: &[u8]) -> Result<Expr, Error> {
+    let mut parser = Parser {
+        lexer: Lexer::new(src).with_comments(true),
+        prev: None,
+        current: None,
+        prev_span: None,
+        current_span: None,
+        abs_depth: 0,
+        percent_operand: false,
+    };
+    parser.advance()?;
+    if parser.current.is_none() {
+        return Err(Error::EmptyExpression);
+    }
+    let expr = parser.statements()?;
+    match parser.current {
+        None => Ok(expr),
+        Some(t) => Err(Error::InvalidToken(t.into())),
+    }
+}
+
+/// A single statement as it is, several as an `Expr::Sequence`.
+pub fn sequence(mut statements: Vec<Expr>) -> Expr {
+    match statements.len() {
+        1 => statements.remove(0),
+        _ => Expr::Sequence(statements),
+    }
+}
+
+struct Parser<S: Scan> {
+    lexer: S,
+    prev: Option<Token>,
+    current: Option<Token>,
+    prev_span: Option<Span>,
+    current_span: Option<Span>,
+    abs_depth: usize,
+    // the operand just parsed ended with a postfix `%`, as in the compiler
+    percent_operand: bool,
+}
+
+impl<S: Scan> Parser<S> {
+    fn advance(&mut self) -> Result<(), Error> {
+        self.prev = self.current;
+        self.prev_span = self.current_span;
+        let mut tok = self.lexer.scan();
+        while matches!(tok, Ok(Token::Comment(_))) {
+            tok = self.lexer.scan();
+        }
+        self.current_span = self.lexer.span();
+        self.current = match tok {
+            Ok(t) => Some(t),
+            Err(LexerError::Eof) => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(())
+    }
+
+    /// Expressions separated by `;`, a trailing one is allowed.
+    fn statements(&mut self) -> Result<Expr, Error> {
+        let mut statements = alloc::vec![self.expression(Priority::Bitwise)?];
+        while self.current == Some(Token::Semicolon) {
+            self.advance()?;
+            if self.current.is_none() {
+                break;
+            }
+            statements.push(self.expression(Priority::Bitwise)?);
+        }
+        Ok(sequence(statements))
+    }
+
+    fn expect(&mut self, target: Token, err: Error) -> Result<(), Error> {
+        if self.current != Some(target) {
+            return Err(err);
+        }
+        self.advance()
+    }
+
+    fn expression(&mut self, priority: Priority) -> Result<Expr, Error> {
+        let mut lhs = self.operand()?;
+        while let Some(tok) = self
+            .current
+            .filter(|t| t.priority() >= priority && !(*t == Token::Bar && self.abs_depth > 0))
+        {
+            self.advance()?;
+            lhs = match tok {
+                Token::Percent => {
+                    self.percent_operand = true;
+                    Expr::Unary {
+                        op: "%".into(),
+                        operand: Box::new(lhs),
+                    }
+                }
+                Token::Div
+                | Token::Plus
+                | Token::Mult
+                | Token::Minus
+                | Token::Ampersand
+                | Token::Bar
+                | Token::ShiftLeft
+                | Token::ShiftRight
+                | Token::Caret => {
+                    // `^` is right associative, as in the compiler
+                    let rhs_priority = match tok {
+                        Token::Caret => tok.priority(),
+                        _ => tok.priority().next(),
+                    };
+                    let rhs = self.expression(rhs_priority)?;
+                    let percent_of =
+                        matches!(tok, Token::Plus | Token::Minus) && self.percent_operand;
+                    self.percent_operand = false;
+                    match rhs {
+                        // `Op::PercentOf` scales the fraction, not the operand of `%`
+                        Expr::Unary { op, operand } if percent_of && op == "%" => Expr::PercentOf {
+                            op: tok.into(),
+                            lhs: Box::new(lhs),
+                            rhs: operand,
+                        },
+                        rhs => Expr::Binary {
+                            op: tok.into(),
+                            lhs: Box::new(lhs),
+                            rhs: Box::new(rhs),
+                        },
+                    }
+                }
+                t => return Err(Error::InvalidToken(t.into())),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn operand(&mut self) -> Result<Expr, Error> {
+        let expr = self.prefix()?;
+        self.percent_operand = false;
+        Ok(expr)
+    }
+
+    fn prefix(&mut self) -> Result<Expr, Error> {
+        // the same errors as `Compiler::expression` for a missing operand
+        let tok = match (self.prev, self.current) {
+            (_, Some(tok)) if tok != Token::Semicolon => tok,
+            (None, None) => return Err(Error::EmptyExpression),
+            (None | Some(Token::Semicolon), Some(current)) => {
+                return Err(Error::ExpectedOperand {
+                    before: current.into(),
+                    offset: self.current_span.map(|span| span.start),
+                })
+            }
+            (prev, current) => {
+                return Err(Error::InvalidTokenBefore {
+                    prev: prev.map_or_else(String::new, String::from),
+                    current: current.map(String::from),
+                })
+            }
+        };
+        self.advance()?;
+        match tok {
+            Token::Minus => Ok(Expr::Unary {
+                op: "-".into(),
+                operand: Box::new(self.expression(Priority::Unary)?),
+            }),
+            Token::Number(digits) => {
+                let digits: &[u8] = digits.into();
+                let num = normalize_number(digits)
+                    .map(Expr::Num)
+                    .map_err(|_| Error::InvalidNumber(Vec::from(digits)))?;
+                // converted at run time, see `Op::Degrees`
+                Ok(match split_angle_suffix(digits).1 {
+                    Some(AngleSuffix::Degrees) => Expr::Unary {
+                        op: "deg".into(),
+                        operand: Box::new(num),
+                    },
+                    Some(AngleSuffix::Radians) => Expr::Unary {
+                        op: "rad".into(),
+                        operand: Box::new(num),
+                    },
+                    None => num,
+                })
+            }
+            Token::LeftParen | Token::LeftBracket => {
+                let abs_depth = core::mem::take(&mut self.abs_depth);
+                let inner = self.expression(Priority::Bitwise)?;
+                let closing = match tok {
+                    Token::LeftParen => Token::RightParen,
+                    _ => Token::RightBracket,
+                };
+                self.expect(closing, Error::UnterminedGroup)?;
+                self.abs_depth = abs_depth;
+                Ok(inner)
+            }
+            Token::Bar => {
+                self.abs_depth += 1;
+                let inner = self.expression(Priority::Bitwise)?;
+                self.abs_depth -= 1;
+                self.expect(Token::Bar, Error::UnterminedAbs)?;
+                Ok(Expr::Call {
+                    name: "abs".into(),
+                    args: alloc::vec![inner],
+                })
+            }
+            Token::Func(_) | Token::Ident(_) => {
+                self.expect(Token::LeftParen, Error::MissingFunctionParen)?;
+                let abs_depth = core::mem::take(&mut self.abs_depth);
+                let args = self.args()?;
+                self.expect(Token::RightParen, Error::MissingFunctionParen)?;
+                self.abs_depth = abs_depth;
+                Ok(Expr::Call {
+                    name: tok.into(),
+                    args,
+                })
+            }
+            Token::Ans if self.current == Some(Token::LeftParen) => {
+                self.advance()?;
+                let index = self.operand()?;
+                self.expect(Token::RightParen, Error::UnterminedGroup)?;
+                Ok(Expr::Call {
+                    name: "ans".into(),
+                    args: alloc::vec![index],
+                })
+            }
+            Token::Ans | Token::Const(_) => Ok(Expr::Name(tok.into())),
+            t if is_binary_operator(t) || t == Token::Percent => Err(Error::ExpectedOperand {
+                before: t.into(),
+                offset: self.prev_span.map(|span| span.start),
+            }),
+            t => Err(Error::InvalidTokenBefore {
+                prev: t.into(),
+                current: self.current.map(String::from),
+            }),
+        }
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>, Error> {
+        let mut args = Vec::new();
+        if self.current == Some(Token::RightParen) {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression(Priority::Bitwise)?);
+            if self.current != Some(Token::Comma) {
+                return Ok(args);
+            }
+            self.advance()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod ast_tests {
+    use super::*;
+
+    fn num(n: f64) -> Box<Expr> {
+        Box::new(Expr::Num(n))
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            parse(b"1 + 2 * 3"),
+            Ok(Expr::Binary {
+                op: "+".into(),
+                lhs: num(1.0),
+                rhs: Box::new(Expr::Binary {
+                    op: "*".into(),
+                    lhs: num(2.0),
+                    rhs: num(3.0),
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn test_power_is_right_associative_and_above_negation() {
+        let tree = parse(b"-2^3^2").unwrap();
+        assert_eq!(tree.pretty(), "-\n  ^\n    2\n    ^\n      3\n      2");
+    }
+
+    #[test]
+    fn test_calls() {
+        let tree = parse(b"max(|1|, ans(2)) % ").unwrap();
+        assert_eq!(
+            tree.pretty(),
+            "%\n  max()\n    abs()\n      1\n    ans()\n      2"
+        );
+    }
+
+    #[test]
+    fn test_angle_suffix() {
+        let tree = parse(b"sin(90deg)").unwrap();
+        assert_eq!(tree.pretty(), "sin()\n  deg\n    90");
+    }
+
+    #[test]
+    fn test_percent_of() {
+        let tree = parse(b"200 + 10%").unwrap();
+        assert_eq!(tree.pretty(), "+ % of\n  200\n  10");
+        // plain fractions, as in the compiler
+        let tree = parse(b"200 + (10%)").unwrap();
+        assert_eq!(tree.pretty(), "+\n  200\n  %\n    10");
+        let tree = parse(b"200 + 2 * 10%").unwrap();
+        assert_eq!(tree.pretty(), "+\n  200\n  *\n    2\n    %\n      10");
+    }
+
+    #[test]
+    fn test_sequence() {
+        let tree = parse(b"2 * 3; ans + 1; # the result\n").unwrap();
+        assert_eq!(tree.pretty(), ";\n  *\n    2\n    3\n  +\n    ans\n    1");
+        assert_eq!(parse(b"1;"), Ok(Expr::Num(1.0)));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(b""), Err(Error::EmptyExpression));
+        assert_eq!(parse(b"(1 + 2"), Err(Error::UnterminedGroup));
+        assert_eq!(parse(b"1 2"), Err(Error::InvalidToken("2".into())));
+    }
+
+    #[test]
+    fn test_errors_match_compiler() {
+        use crate::compiler::{Compile, Compiler};
+
+        for src in [&b"*2"[..], b"1 + %", b"1 +", b";", b"1;;2", b"2 - ;"] {
+            let compiled = Compiler::default().compile(&mut Lexer::new(src));
+            assert_eq!(parse(src).map(|_| ()), compiled, "{:?}", src);
+        }
+        assert_eq!(
+            parse(b"*2"),
+            Err(Error::ExpectedOperand {
+                before: "*".into(),
+                offset: Some(0),
+            })
+        );
+    }
+}
@@ -1,10 +1,20 @@
+use alloc::{string::String, vec::Vec};
+
 use crate::{
-    lexer::{Error as LexerError, FuncType, Priority, Scan, Token},
+    lexer::{
+        normalize_number, split_angle_suffix, AngleSuffix, Error as LexerError, FuncType, Lexer,
+        Priority, Scan, Span, Token,
+    },
+    math,
     misc::i8_as_u8,
 };
 
 pub trait Compile {
     fn compile(&mut self, lexer: &mut impl Scan) -> Result<(), Error>;
+
+    /// Append the compiled code to `out` instead of keeping it, leaving `out`
+    /// as it was on error.
+    fn compile_into(&mut self, lexer: &mut impl Scan, out: &mut Vec<u8>) -> Result<(), Error>;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -16,19 +26,132 @@ pub enum Error {
         current: Option<String>,
     },
     UnterminedGroup,
+    UnterminedAbs,
+    InvalidAnsIndex(String),
     InvalidToken(String),
     MissingFunctionParen,
     MissingCommaInFunctionCall,
+    MissingFunctionArgs(FuncType),
+    TooManyFunctionArgs(FuncType),
+    IntegerPrecisionLoss(String),
+    WrongArity {
+        func: String,
+        expected: usize,
+        found: usize,
+    },
+    TooManyTokens(usize),
+    /// A call of a registered function with more than 255 arguments.
+    TooManyExternArgs(String),
+    /// The source holds no tokens at all.
+    EmptyExpression,
+    /// `ans` or `ans(n)` compiled with `with_forbid_ans(true)`.
+    AnsNotAllowed,
+    /// A binary or postfix operator where an operand should start, as in `* 2`.
+    /// `offset` is where the operator starts, `None` if the lexer has no spans.
+    ExpectedOperand {
+        before: String,
+        offset: Option<usize>,
+    },
+}
+
+impl Error {
+    /// Stable machine-readable identifier of the error kind. Lexer errors keep
+    /// the code of the lexer.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::FromLexer(e) => e.code(),
+            Self::InvalidNumber(_) => "E_INVALID_NUMBER",
+            Self::InvalidTokenBefore { .. } => "E_INVALID_TOKEN_BEFORE",
+            Self::UnterminedGroup => "E_UNTERMINATED_GROUP",
+            Self::UnterminedAbs => "E_UNTERMINATED_ABS",
+            Self::InvalidAnsIndex(_) => "E_INVALID_ANS_INDEX",
+            Self::InvalidToken(_) => "E_INVALID_TOKEN",
+            Self::MissingFunctionParen => "E_MISSING_FUNCTION_PAREN",
+            Self::MissingCommaInFunctionCall => "E_MISSING_COMMA",
+            Self::MissingFunctionArgs(_) => "E_MISSING_FUNCTION_ARGS",
+            Self::TooManyFunctionArgs(_) => "E_TOO_MANY_FUNCTION_ARGS",
+            Self::IntegerPrecisionLoss(_) => "E_INTEGER_PRECISION_LOSS",
+            Self::WrongArity { .. } => "E_WRONG_ARITY",
+            Self::TooManyTokens(_) => "E_TOO_MANY_TOKENS",
+            Self::TooManyExternArgs(_) => "E_TOO_MANY_EXTERN_ARGS",
+            Self::EmptyExpression => "E_EMPTY_EXPRESSION",
+            Self::AnsNotAllowed => "E_ANS_NOT_ALLOWED",
+            Self::ExpectedOperand { .. } => "E_EXPECTED_OPERAND",
+        }
+    }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::FromLexer(e) => write!(f, "{}", e),
+            Self::InvalidNumber(digits) => {
+                write!(f, "invalid number '{}'", String::from_utf8_lossy(digits))
+            }
+            Self::InvalidTokenBefore { prev, current } => match current {
+                Some(current) => write!(f, "unexpected token '{}' before '{}'", prev, current),
+                None if prev.is_empty() => f.write_str("unexpected end of input"),
+                None => write!(f, "unexpected token '{}' at the end", prev),
+            },
+            Self::UnterminedGroup => f.write_str("unclosed parenthesis"),
+            Self::UnterminedAbs => f.write_str("unclosed '|'"),
+            Self::InvalidAnsIndex(index) => write!(f, "invalid ans index '{}'", index),
+            Self::InvalidToken(tok) => write!(f, "unexpected token '{}'", tok),
+            Self::MissingFunctionParen => f.write_str("missing parenthesis in function call"),
+            Self::MissingCommaInFunctionCall => {
+                f.write_str("missing ',' between function arguments")
+            }
+            Self::MissingFunctionArgs(func) => write!(f, "missing arguments for {}", func.name()),
+            Self::TooManyFunctionArgs(func) => write!(f, "too many arguments for {}", func.name()),
+            Self::IntegerPrecisionLoss(literal) => {
+                write!(
+                    f,
+                    "integer literal '{}' cannot be represented exactly",
+                    literal
+                )
+            }
+            Self::WrongArity {
+                func,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} argument(s), found {}",
+                func, expected, found
+            ),
+            Self::TooManyTokens(limit) => write!(f, "more than {} tokens", limit),
+            Self::TooManyExternArgs(name) => write!(f, "too many arguments for {}", name),
+            Self::EmptyExpression => f.write_str("empty expression"),
+            Self::AnsNotAllowed => f.write_str("'ans' is not allowed here"),
+            Self::ExpectedOperand {
+                before,
+                offset: Some(offset),
+            } => write!(
+                f,
+                "expected an operand before '{}' at offset {}",
+                before, offset
+            ),
+            Self::ExpectedOperand {
+                before,
+                offset: None,
+            } => write!(f, "expected an operand before '{}'", before),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
+/// Problems that do not stop the compilation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// An integer literal above 2^53, which an `f64` may not represent exactly.
+    IntegerPrecisionLoss(String),
+    /// Parentheses around a single operand, as in `(3)` or `((1 + 2))`, if the
+    /// compiler was built `with_lint_parens(true)`. The span covers both parens.
+    RedundantParens(Option<Span>),
+}
+
 impl From<LexerError> for Error {
     fn from(value: LexerError) -> Self {
         Self::FromLexer(value)
@@ -47,6 +170,64 @@ pub enum Op {
     Func = 6,
     Ans = 7,
     NumberI8 = 8,
+    BitAnd = 9,
+    BitOr = 10,
+    ShiftLeft = 11,
+    ShiftRight = 12,
+    AnsN = 13,
+    Percent = 14,
+    PercentOf = 15,
+    Const = 16,
+    /// Ends the program, the value on top of the stack is the result.
+    Halt = 17,
+    /// Followed by the argument count, the name length and the name of a
+    /// function registered with `VirtualMachine::register_fn`.
+    CallExtern = 18,
+    /// Pops the result of an expression followed by `;`, making it `ans` for
+    /// the next one.
+    SetAns = 19,
+    /// Converts the value on top of the stack from degrees to the angle mode
+    /// of the machine, for a `deg` suffix.
+    Degrees = 20,
+    /// Converts the value on top of the stack from radians to the angle mode
+    /// of the machine, for a `rad` suffix.
+    Radians = 21,
+}
+
+impl Op {
+    /// Assembly-like name, as shown in listings and traces.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Op::Number => "PUSH_F64",
+            Op::Plus => "ADD",
+            Op::Minus => "SUB",
+            Op::Mult => "MUL",
+            Op::Div => "DIV",
+            Op::Negate => "NEG",
+            Op::Func => "CALL",
+            Op::Ans => "ANS",
+            Op::NumberI8 => "PUSH_I8",
+            Op::BitAnd => "AND",
+            Op::BitOr => "OR",
+            Op::ShiftLeft => "SHL",
+            Op::ShiftRight => "SHR",
+            Op::AnsN => "ANS_N",
+            Op::Percent => "PERCENT",
+            Op::PercentOf => "PERCENT_OF",
+            Op::Const => "CONST",
+            Op::Halt => "HALT",
+            Op::CallExtern => "CALL_EXTERN",
+            Op::SetAns => "SET_ANS",
+            Op::Degrees => "DEG",
+            Op::Radians => "RAD",
+        }
+    }
+}
+
+impl core::fmt::Display for Op {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.mnemonic())
+    }
 }
 
 impl From<Op> for u8 {
@@ -56,7 +237,7 @@ impl From<Op> for u8 {
 }
 
 #[derive(Debug)]
-pub struct InvalidOpcode(u8);
+pub struct InvalidOpcode(pub u8);
 
 impl TryFrom<u8> for Op {
     type Error = InvalidOpcode;
@@ -71,6 +252,19 @@ impl TryFrom<u8> for Op {
             6 => Ok(Op::Func),
             7 => Ok(Op::Ans),
             8 => Ok(Op::NumberI8),
+            9 => Ok(Op::BitAnd),
+            10 => Ok(Op::BitOr),
+            11 => Ok(Op::ShiftLeft),
+            12 => Ok(Op::ShiftRight),
+            13 => Ok(Op::AnsN),
+            14 => Ok(Op::Percent),
+            15 => Ok(Op::PercentOf),
+            16 => Ok(Op::Const),
+            17 => Ok(Op::Halt),
+            18 => Ok(Op::CallExtern),
+            19 => Ok(Op::SetAns),
+            20 => Ok(Op::Degrees),
+            21 => Ok(Op::Radians),
             x => Err(InvalidOpcode(x)),
         }
     }
@@ -79,20 +273,62 @@ impl TryFrom<u8> for Op {
 pub struct Compiler {
     prev_token: Option<Token>,
     current_token: Option<Token>,
+    prev_span: Option<Span>,
+    current_span: Option<Span>,
     chunk: Vec<u8>,
+    abs_depth: usize,
+    // the operand just parsed ended with a postfix `%`
+    percent_operand: bool,
+    compact_numbers: bool,
+    strict_integers: bool,
+    diagnostics: Vec<Diagnostic>,
+    max_tokens: Option<usize>,
+    token_count: usize,
+    uses_ans: bool,
+    halt: bool,
+    forbid_ans: bool,
+    lint_parens: bool,
+    empty_is_zero: bool,
+    // an operator was parsed in the innermost open group
+    operator_seen: bool,
 }
 
 pub type CompilerResult = Result<(), Error>;
 
 impl Compile for Compiler {
     fn compile(&mut self, lexer: &mut impl Scan) -> CompilerResult {
-        self.advance(lexer)?;
-        self.expression(lexer, Priority::Term)?;
+        match self.begin(lexer) {
+            Err(Error::EmptyExpression) if self.empty_is_zero => {
+                self.chunk.push(Op::NumberI8.into());
+                self.chunk.push(0);
+                self.emit_halt();
+                return Ok(());
+            }
+            res => res?,
+        }
+        self.expressions(lexer)?;
 
         match self.current_token {
             Some(t) => Err(Error::InvalidToken(t.into())),
-            None => Ok(()),
+            None => {
+                self.emit_halt();
+                Ok(())
+            }
+        }
+    }
+
+    /// The compiler is reset first and writes straight into `out`, so several
+    /// expressions can share one buffer without copies.
+    fn compile_into(&mut self, lexer: &mut impl Scan, out: &mut Vec<u8>) -> CompilerResult {
+        self.reset();
+        let start = out.len();
+        core::mem::swap(&mut self.chunk, out);
+        let res = self.compile(lexer);
+        core::mem::swap(&mut self.chunk, out);
+        if res.is_err() {
+            out.truncate(start);
         }
+        res
     }
 }
 
@@ -105,45 +341,346 @@ impl Default for Compiler {
             chunk,
             prev_token: None,
             current_token: None,
+            prev_span: None,
+            current_span: None,
+            abs_depth: 0,
+            percent_operand: false,
+            compact_numbers: true,
+            strict_integers: false,
+            diagnostics: Vec::new(),
+            max_tokens: None,
+            token_count: 0,
+            uses_ans: false,
+            halt: false,
+            forbid_ans: false,
+            lint_parens: false,
+            empty_is_zero: false,
+            operator_seen: false,
         }
     }
 }
 
 impl Compiler {
+    /// With `false` every number is emitted as `Op::Number` followed by 8 bytes,
+    /// never as the shorter `Op::NumberI8`. The default is `true`.
+    pub fn with_compact_numbers(mut self, compact: bool) -> Self {
+        self.compact_numbers = compact;
+        self
+    }
+
+    pub fn compact_numbers(&self) -> bool {
+        self.compact_numbers
+    }
+
+    /// With `true` an integer literal above 2^53 is an `Error::IntegerPrecisionLoss`
+    /// instead of a `Diagnostic`. The default is `false`.
+    pub fn with_strict_integers(mut self, strict: bool) -> Self {
+        self.strict_integers = strict;
+        self
+    }
+
+    pub fn strict_integers(&self) -> bool {
+        self.strict_integers
+    }
+
+    /// Fail with `Error::TooManyTokens` once more than `max` tokens are scanned.
+    /// The default is no limit.
+    pub fn with_max_tokens(mut self, max: Option<usize>) -> Self {
+        self.max_tokens = max;
+        self
+    }
+
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    /// End every compiled chunk with `Op::Halt`. The default is `false`, the
+    /// program then ends with its last instruction.
+    pub fn with_halt(mut self, halt: bool) -> Self {
+        self.halt = halt;
+        self
+    }
+
+    pub fn halt(&self) -> bool {
+        self.halt
+    }
+
+    /// Make `ans` and `ans(n)` an `Error::AnsNotAllowed`, for stateless
+    /// evaluation where there is no previous result. The default is `false`.
+    pub fn with_forbid_ans(mut self, forbid: bool) -> Self {
+        self.forbid_ans = forbid;
+        self
+    }
+
+    pub fn forbid_ans(&self) -> bool {
+        self.forbid_ans
+    }
+
+    /// Report `Diagnostic::RedundantParens`. The default is `false`.
+    pub fn with_lint_parens(mut self, lint: bool) -> Self {
+        self.lint_parens = lint;
+        self
+    }
+
+    pub fn lint_parens(&self) -> bool {
+        self.lint_parens
+    }
+
+    /// Compile blank input to a program evaluating to `0.0` instead of failing
+    /// with `Error::EmptyExpression`. The default is `false`.
+    pub fn with_empty_is_zero(mut self, empty_is_zero: bool) -> Self {
+        self.empty_is_zero = empty_is_zero;
+        self
+    }
+
+    pub fn empty_is_zero(&self) -> bool {
+        self.empty_is_zero
+    }
+
+    /// Scan the first token, which must exist.
+    fn begin(&mut self, lexer: &mut impl Scan) -> CompilerResult {
+        self.advance(lexer)?;
+        match self.current_token {
+            Some(_) => Ok(()),
+            None => Err(Error::EmptyExpression),
+        }
+    }
+
+    /// Expressions separated by `;`, each one the `ans` of the next. A trailing
+    /// `;` is allowed, the last expression is the result.
+    fn expressions(&mut self, lexer: &mut impl Scan) -> CompilerResult {
+        self.expression(lexer, Priority::Bitwise)?;
+        self.next_expressions(lexer)
+    }
+
+    /// The expressions after the first one, each following a `;`.
+    fn next_expressions(&mut self, lexer: &mut impl Scan) -> CompilerResult {
+        while self.current_token == Some(Token::Semicolon) {
+            self.advance(lexer)?;
+            if self.current_token.is_none() {
+                break;
+            }
+            self.chunk.push(Op::SetAns.into());
+            self.expression(lexer, Priority::Bitwise)?;
+        }
+        Ok(())
+    }
+
+    fn emit_halt(&mut self) {
+        if self.halt {
+            self.chunk.push(Op::Halt.into());
+        }
+    }
+
+    /// Warnings collected by the last `compile`, cleared by `reset`.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Whether the code compiled since the last `reset` reads `ans` or `ans(n)`.
+    pub fn uses_ans(&self) -> bool {
+        self.uses_ans
+    }
+
     pub fn opcodes(&self) -> &[u8] {
         &self.chunk
     }
 
+    /// The most values the compiled chunk keeps on the stack at once, to size
+    /// it with `VirtualMachine::with_stack_capacity`.
+    pub fn max_stack_depth(&self) -> usize {
+        max_stack_depth(&self.chunk)
+    }
+
+    /// Move the compiled chunk out, leaving the compiler ready for reuse.
+    pub fn take_opcodes(&mut self) -> Vec<u8> {
+        let chunk = core::mem::replace(&mut self.chunk, Vec::with_capacity(INITIAL_CHUNK_SIZE));
+        self.reset();
+        chunk
+    }
+
+    /// Compile `src` with a fresh default compiler and return the owned chunk,
+    /// for one-off use where no state should outlive the call.
+    pub fn compile_once(src: &str) -> Result<Vec<u8>, Error> {
+        let mut compiler = Self::default();
+        compiler.compile(&mut Lexer::new(src.as_bytes()))?;
+        Ok(compiler.take_opcodes())
+    }
+
     pub fn reset(&mut self) {
         self.chunk.clear();
         self.prev_token = None;
         self.current_token = None;
+        self.prev_span = None;
+        self.current_span = None;
+        self.abs_depth = 0;
+        self.percent_operand = false;
+        self.diagnostics.clear();
+        self.token_count = 0;
+        self.uses_ans = false;
+        self.operator_seen = false;
+    }
+
+    /// Compile like `compile`, but after an error skip to the next binary operator,
+    /// `;` or `)` and go on, so that all the problems are reported at once. Spans are
+    /// available if the lexer tracks them. The chunk is meaningless when errors are returned.
+    pub fn compile_collecting(
+        &mut self,
+        lexer: &mut impl Scan,
+    ) -> Result<(), Vec<(Option<Span>, Error)>> {
+        let mut errors = Vec::new();
+        let mut res = self.begin(lexer).and_then(|_| self.expressions(lexer));
+        loop {
+            let lexer_failed = match res {
+                Ok(()) => match self.current_token {
+                    None => break,
+                    Some(t) => {
+                        errors.push((self.current_span, Error::InvalidToken(t.into())));
+                        false
+                    }
+                },
+                Err(e) => {
+                    let lexer_failed = matches!(e, Error::FromLexer(_));
+                    errors.push((self.error_span(&e), e));
+                    lexer_failed
+                }
+            };
+            if !self.synchronize(lexer, &mut errors, lexer_failed) {
+                break;
+            }
+            self.abs_depth = 0;
+            res = self
+                .infix(lexer, Priority::Bitwise)
+                .and_then(|_| self.next_expressions(lexer));
+        }
+        if errors.is_empty() {
+            self.emit_halt();
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Skip tokens up to a binary operator or a `;`, which is left as the current
+    /// token, or past a `)`. Returns `false` at the end of the input.
+    fn synchronize(
+        &mut self,
+        lexer: &mut impl Scan,
+        errors: &mut Vec<(Option<Span>, Error)>,
+        mut lexer_failed: bool,
+    ) -> bool {
+        loop {
+            let stop = match self.current_token {
+                _ if lexer_failed => {
+                    if !lexer.skip_invalid() {
+                        return false;
+                    }
+                    false
+                }
+                None => return false,
+                Some(t) if is_binary_operator(t) || t == Token::Semicolon => return true,
+                Some(t) => matches!(t, Token::RightParen | Token::RightBracket),
+            };
+            lexer_failed = false;
+            match self.advance(lexer) {
+                Err(e) => {
+                    errors.push((self.current_span, e));
+                    lexer_failed = true;
+                }
+                Ok(()) if stop => return true,
+                Ok(()) => {}
+            }
+        }
+    }
+
+    /// Errors about a token that was already consumed point at the previous span.
+    fn error_span(&self, e: &Error) -> Option<Span> {
+        match e {
+            Error::InvalidTokenBefore { .. }
+            | Error::InvalidToken(_)
+            | Error::InvalidNumber(_)
+            | Error::IntegerPrecisionLoss(_)
+            | Error::AnsNotAllowed => self.prev_span,
+            // a `;` missing its operand is reported without consuming it
+            Error::ExpectedOperand { offset, .. }
+                if *offset == self.current_span.map(|span| span.start) =>
+            {
+                self.current_span
+            }
+            Error::ExpectedOperand { .. } => self.prev_span,
+            _ => self.current_span,
+        }
     }
 
     fn expression(&mut self, lexer: &mut impl Scan, priority: Priority) -> CompilerResult {
+        // an operand is missing, and the `;` is left for the next statement
+        if matches!(self.current_token, None | Some(Token::Semicolon)) {
+            return Err(match (self.prev_token, self.current_token) {
+                (None, None) => Error::EmptyExpression,
+                (None | Some(Token::Semicolon), Some(current)) => Error::ExpectedOperand {
+                    before: current.into(),
+                    offset: self.current_span.map(|span| span.start),
+                },
+                (prev, current) => Error::InvalidTokenBefore {
+                    prev: prev.map_or_else(String::new, String::from),
+                    current: current.map(String::from),
+                },
+            });
+        }
         self.advance(lexer)?;
         if let Some(prev) = self.prev_token {
             match prev {
                 Token::Minus => self.emit_unary(lexer),
                 Token::Number(num_str) => self.emit_number(num_str.into()),
-                Token::LeftParen => self.parse_group(lexer),
+                Token::LeftParen => self.parse_group(lexer, Token::RightParen),
+                Token::LeftBracket => self.parse_group(lexer, Token::RightBracket),
+                Token::Bar => self.parse_abs(lexer),
                 Token::Func(func_type) => self.parse_fn(lexer, func_type),
-                Token::Ans => {
-                    self.chunk.push(Op::Ans.into());
+                Token::Ident(name) => self.parse_extern(lexer, name.into()),
+                Token::Ans => self.parse_ans(lexer),
+                Token::Const(constant) => {
+                    self.chunk.push(Op::Const.into());
+                    self.chunk.push(constant.into());
                     Ok(())
                 }
+                // a bar opens an absolute value and a minus negates, the
+                // other operators need an operand before them
+                t if is_binary_operator(t) || t == Token::Percent => Err(Error::ExpectedOperand {
+                    before: t.into(),
+                    offset: self.prev_span.map(|span| span.start),
+                }),
                 t => Err(Error::InvalidTokenBefore {
                     prev: t.into(),
                     current: self.current_token.map(|tok| tok.into()),
                 }),
             }?;
+            self.percent_operand = false;
         }
-        while self.current_token.is_some_and(|t| t.priority() >= priority) {
+        self.infix(lexer, priority)
+    }
+
+    fn infix(&mut self, lexer: &mut impl Scan, priority: Priority) -> CompilerResult {
+        while self
+            .current_token
+            .is_some_and(|t| t.priority() >= priority && !self.closes_abs(t))
+        {
             self.advance(lexer)?;
+            self.operator_seen = true;
             if let Some(prev) = self.prev_token {
                 match prev {
-                    Token::Div | Token::Plus | Token::Mult | Token::Minus => {
-                        self.parse_binary(lexer, prev)
+                    Token::Div
+                    | Token::Plus
+                    | Token::Mult
+                    | Token::Minus
+                    | Token::Ampersand
+                    | Token::Bar
+                    | Token::ShiftLeft
+                    | Token::ShiftRight
+                    | Token::Caret => self.parse_binary(lexer, prev),
+                    Token::Percent => {
+                        self.chunk.push(Op::Percent.into());
+                        self.percent_operand = true;
+                        Ok(())
                     }
                     t => Err(Error::InvalidToken(t.into())),
                 }?;
@@ -152,8 +689,21 @@ impl Compiler {
         Ok(())
     }
 
+    /// A postfix `%` divides by 100. When it ends the right operand of `+` or `-`
+    /// the percentage is taken of the left operand instead, so `200 + 10%` is
+    /// `200 + 20`. Only a bare `x%` counts: `200 + (10%)` and `200 + 2 * 10%`
+    /// add the plain fractions.
     fn parse_binary(&mut self, lexer: &mut impl Scan, tok: Token) -> CompilerResult {
-        self.expression(lexer, tok.priority().next())?;
+        // `^` is right associative: `2^3^2` is `2^(3^2)`
+        let priority = match tok {
+            Token::Caret => tok.priority(),
+            _ => tok.priority().next(),
+        };
+        self.expression(lexer, priority)?;
+        if matches!(tok, Token::Plus | Token::Minus) && self.percent_operand {
+            self.chunk.push(Op::PercentOf.into());
+        }
+        self.percent_operand = false;
         match tok {
             Token::Minus => {
                 self.chunk.push(Op::Minus.into());
@@ -171,14 +721,46 @@ impl Compiler {
                 self.chunk.push(Op::Mult.into());
                 Ok(())
             }
+            Token::Ampersand => {
+                self.chunk.push(Op::BitAnd.into());
+                Ok(())
+            }
+            Token::Bar => {
+                self.chunk.push(Op::BitOr.into());
+                Ok(())
+            }
+            Token::ShiftLeft => {
+                self.chunk.push(Op::ShiftLeft.into());
+                Ok(())
+            }
+            Token::ShiftRight => {
+                self.chunk.push(Op::ShiftRight.into());
+                Ok(())
+            }
+            Token::Caret => {
+                self.chunk.push(Op::Func.into());
+                self.chunk.push(FuncType::Pow.into());
+                Ok(())
+            }
             t => Err(Error::InvalidToken(t.into())),
         }
     }
 
     fn advance(&mut self, lexer: &mut impl Scan) -> CompilerResult {
         self.prev_token = self.current_token;
-        let tok = lexer.scan();
+        self.prev_span = self.current_span;
+        let mut tok = lexer.scan();
+        while matches!(tok, Ok(Token::Comment(_))) {
+            tok = lexer.scan();
+        }
         self.current_token = tok.ok();
+        self.current_span = lexer.span();
+        if tok.is_ok() {
+            self.token_count += 1;
+            if let Some(max) = self.max_tokens.filter(|&max| self.token_count > max) {
+                return Err(Error::TooManyTokens(max));
+            }
+        }
         tok.map_or_else(
             |e| {
                 if e != LexerError::Eof {
@@ -199,63 +781,274 @@ impl Compiler {
         }
     }
 
-    fn parse_group(&mut self, lexer: &mut impl Scan) -> CompilerResult {
-        self.expression(lexer, Priority::Term)?;
+    /// Inside an absolute value group a bar after an operand closes the group
+    /// instead of being a bitwise or: write `|(a | b)|` for the latter.
+    fn closes_abs(&self, tok: Token) -> bool {
+        tok == Token::Bar && self.abs_depth > 0
+    }
+
+    /// `closing` is the token matching the one that opened the group, so
+    /// `(1]` is unterminated.
+    /// Operators inside a nested group do not count for the enclosing one, so
+    /// `((1 + 2))` is redundant, but those in function arguments do.
+    fn parse_group(&mut self, lexer: &mut impl Scan, closing: Token) -> CompilerResult {
+        let abs_depth = core::mem::take(&mut self.abs_depth);
+        let operator_seen = core::mem::take(&mut self.operator_seen);
+        let open = self.prev_span;
+        self.expression(lexer, Priority::Bitwise)?;
+        self.consume(lexer, closing, Error::UnterminedGroup)?;
+        if self.lint_parens && !self.operator_seen {
+            let span = open.zip(self.prev_span).map(|(open, close)| Span {
+                start: open.start,
+                end: close.end,
+            });
+            self.diagnostics.push(Diagnostic::RedundantParens(span));
+        }
+        self.abs_depth = abs_depth;
+        self.operator_seen = operator_seen;
+        Ok(())
+    }
+
+    /// A bar in operand position opens an absolute value group and the first bar found
+    /// after a complete operand closes it, so `||x| - 1|` nests as expected. Adjacent
+    /// groups must be joined by an operator: `|a||b|` is not read as `|a| * |b|`.
+    fn parse_abs(&mut self, lexer: &mut impl Scan) -> CompilerResult {
+        self.abs_depth += 1;
+        self.expression(lexer, Priority::Bitwise)?;
+        self.abs_depth -= 1;
+        self.consume(lexer, Token::Bar, Error::UnterminedAbs)?;
+        self.chunk.push(Op::Func.into());
+        self.chunk.push(FuncType::Abs.into());
+        Ok(())
+    }
+
+    /// `ans` alone is the previous result, `ans(n)` the result `n` evaluations ago.
+    fn parse_ans(&mut self, lexer: &mut impl Scan) -> CompilerResult {
+        if self.forbid_ans {
+            return Err(Error::AnsNotAllowed);
+        }
+        self.uses_ans = true;
+        if self.current_token != Some(Token::LeftParen) {
+            self.chunk.push(Op::Ans.into());
+            return Ok(());
+        }
+        self.advance(lexer)?;
+        let index = match self.current_token {
+            Some(Token::Number(digits)) => {
+                let digits: &[u8] = digits.into();
+                core::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|d| d.parse::<u8>().ok())
+                    .filter(|&i| i > 0)
+                    .ok_or_else(|| {
+                        Error::InvalidAnsIndex(String::from_utf8_lossy(digits).into_owned())
+                    })?
+            }
+            t => {
+                return Err(Error::InvalidAnsIndex(
+                    t.map_or_else(String::new, String::from),
+                ))
+            }
+        };
+        self.advance(lexer)?;
         self.consume(lexer, Token::RightParen, Error::UnterminedGroup)?;
+        self.chunk.push(Op::AnsN.into());
+        self.chunk.push(index);
         Ok(())
     }
 
     fn parse_fn(&mut self, lexer: &mut impl Scan, func_type: FuncType) -> CompilerResult {
         self.consume(lexer, Token::LeftParen, Error::MissingFunctionParen)?;
+        let abs_depth = core::mem::take(&mut self.abs_depth);
+        let count = self.parse_args(lexer)?;
         let arity = func_type.arity();
-        if arity > 0 {
-            for _ in 0..arity - 1 {
-                self.expression(lexer, Priority::Term)?;
-                self.consume(lexer, Token::Comma, Error::MissingCommaInFunctionCall)?;
+        if func_type.is_variadic() {
+            if count == 0 {
+                return Err(Error::MissingFunctionArgs(func_type));
             }
-            self.expression(lexer, Priority::Term)?;
+        } else if count < arity && self.current_token != Some(Token::RightParen) {
+            return Err(Error::MissingCommaInFunctionCall);
+        } else if count != arity {
+            return Err(Error::WrongArity {
+                func: func_type.into(),
+                expected: arity,
+                found: count,
+            });
         }
         self.consume(lexer, Token::RightParen, Error::MissingFunctionParen)?;
+        self.abs_depth = abs_depth;
         self.chunk.push(Op::Func.into());
         self.chunk.push(func_type.into());
+        if func_type.is_variadic() {
+            let count = u8::try_from(count).map_err(|_| Error::TooManyFunctionArgs(func_type))?;
+            self.chunk.push(count);
+        }
+        Ok(())
+    }
+
+    /// The arity is only known to the virtual machine, which checks it when calling.
+    fn parse_extern(&mut self, lexer: &mut impl Scan, name: &[u8]) -> CompilerResult {
+        self.consume(lexer, Token::LeftParen, Error::MissingFunctionParen)?;
+        let abs_depth = core::mem::take(&mut self.abs_depth);
+        let count = self.parse_args(lexer)?;
+        self.consume(lexer, Token::RightParen, Error::MissingFunctionParen)?;
+        self.abs_depth = abs_depth;
+        let name_str = || String::from_utf8_lossy(name).into_owned();
+        let count = u8::try_from(count).map_err(|_| Error::TooManyExternArgs(name_str()))?;
+        let len = u8::try_from(name.len()).map_err(|_| Error::InvalidToken(name_str()))?;
+        self.chunk.push(Op::CallExtern.into());
+        self.chunk.push(count);
+        self.chunk.push(len);
+        self.chunk.extend_from_slice(name);
         Ok(())
     }
 
+    /// Parse comma separated arguments up to the closing paren, returning their count.
+    fn parse_args(&mut self, lexer: &mut impl Scan) -> Result<usize, Error> {
+        if self.current_token == Some(Token::RightParen) {
+            return Ok(0);
+        }
+        let mut count = 0;
+        loop {
+            self.expression(lexer, Priority::Bitwise)?;
+            count += 1;
+            if self.current_token != Some(Token::Comma) {
+                return Ok(count);
+            }
+            self.advance(lexer)?;
+        }
+    }
+
     fn emit_unary(&mut self, lexer: &mut impl Scan) -> CompilerResult {
+        // `Unary` is below `Power`, so the operand of `-2^2` is `2^2`
+        self.operator_seen = true;
         self.expression(lexer, Priority::Unary)?;
         self.chunk.push(Op::Negate.into());
         Ok(())
     }
 
     fn emit_number(&mut self, digits: &[u8]) -> CompilerResult {
-        let num = std::str::from_utf8(digits)
-            .ok()
-            .and_then(|chars| chars.parse::<f64>().ok());
-
-        match num {
-            Some(n) => {
-                if (i8::MIN as f64..=i8::MAX as f64).contains(&n) && n.fract() == 0.0 {
+        if loses_integer_precision(digits) {
+            let literal = String::from_utf8_lossy(digits).into_owned();
+            if self.strict_integers {
+                return Err(Error::IntegerPrecisionLoss(literal));
+            }
+            self.diagnostics
+                .push(Diagnostic::IntegerPrecisionLoss(literal));
+        }
+        match normalize_number(digits) {
+            Ok(n) => {
+                if self.compact_numbers
+                    && (i8::MIN as f64..=i8::MAX as f64).contains(&n)
+                    && math::fract(n) == 0.0
+                {
                     self.chunk.push(Op::NumberI8.into());
                     self.chunk.push(i8_as_u8(n as i8));
                 } else {
                     self.chunk.push(Op::Number.into());
-                    let old_len = self.chunk.len();
-                    let f64_bytes = 8;
-                    self.chunk.resize(old_len + f64_bytes, 0);
-                    let p = self.chunk[old_len..].as_mut_ptr();
-                    let pn = std::ptr::from_ref(&n) as *const u8;
-                    unsafe { pn.copy_to_nonoverlapping(p, f64_bytes) };
+                    self.chunk.extend_from_slice(&write_f64_operand(n));
                 }
                 Ok(())
             }
-            None => Err(Error::InvalidNumber(Vec::from(digits))),
+            Err(_) => Err(Error::InvalidNumber(Vec::from(digits))),
+        }?;
+        match split_angle_suffix(digits).1 {
+            Some(AngleSuffix::Degrees) => self.chunk.push(Op::Degrees.into()),
+            Some(AngleSuffix::Radians) => self.chunk.push(Op::Radians.into()),
+            None => {}
         }
+        Ok(())
+    }
+}
+
+/// Simulate the stack effect of every instruction. A malformed chunk is only
+/// measured up to the first invalid byte.
+fn max_stack_depth(chunk: &[u8]) -> usize {
+    let (mut depth, mut max, mut ip) = (0usize, 0usize, 0);
+    while let Some(op) = chunk.get(ip).and_then(|&b| Op::try_from(b).ok()) {
+        ip += 1;
+        let (pops, pushes, operand_bytes) = match op {
+            Op::Number => (0, 1, 8),
+            Op::NumberI8 | Op::AnsN | Op::Const => (0, 1, 1),
+            Op::Ans => (0, 1, 0),
+            Op::Plus
+            | Op::Minus
+            | Op::Mult
+            | Op::Div
+            | Op::BitAnd
+            | Op::BitOr
+            | Op::ShiftLeft
+            | Op::ShiftRight => (2, 1, 0),
+            Op::Negate | Op::Percent | Op::PercentOf | Op::Degrees | Op::Radians => (1, 1, 0),
+            Op::SetAns => (1, 0, 0),
+            Op::Func => {
+                let Some(func_type) = chunk.get(ip).and_then(|&b| FuncType::try_from(b).ok())
+                else {
+                    break;
+                };
+                if func_type.is_variadic() {
+                    let Some(&count) = chunk.get(ip + 1) else {
+                        break;
+                    };
+                    (count as usize, 1, 2)
+                } else {
+                    (func_type.arity(), 1, 1)
+                }
+            }
+            Op::CallExtern => {
+                let (Some(&count), Some(&len)) = (chunk.get(ip), chunk.get(ip + 1)) else {
+                    break;
+                };
+                (count as usize, 1, 2 + len as usize)
+            }
+            Op::Halt => break,
+        };
+        depth = depth.saturating_sub(pops) + pushes;
+        max = max.max(depth);
+        ip += operand_bytes;
     }
+    max
+}
+
+pub(crate) fn is_binary_operator(t: Token) -> bool {
+    matches!(
+        t,
+        Token::Div
+            | Token::Plus
+            | Token::Mult
+            | Token::Minus
+            | Token::Ampersand
+            | Token::Bar
+            | Token::ShiftLeft
+            | Token::ShiftRight
+            | Token::Caret
+    )
+}
+
+/// Encode the operand of `Op::Number`. It is little endian whatever the host,
+/// so a chunk can be moved between machines.
+pub fn write_f64_operand(n: f64) -> [u8; 8] {
+    n.to_le_bytes()
+}
+
+/// Decode the operand of `Op::Number`, see `write_f64_operand`.
+pub fn read_f64_operand(bytes: &[u8; 8]) -> f64 {
+    f64::from_le_bytes(*bytes)
+}
+
+const MAX_EXACT_INTEGER: u64 = 1 << f64::MANTISSA_DIGITS;
+
+fn loses_integer_precision(digits: &[u8]) -> bool {
+    digits.iter().all(u8::is_ascii_digit)
+        && core::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| d.parse::<u64>().ok())
+            .is_none_or(|n| n > MAX_EXACT_INTEGER)
 }
 
 #[cfg(test)]
 mod compiler_tests {
-    use crate::misc::u8_as_i8;
+    use crate::{lexer::Constant, misc::u8_as_i8};
 
     use super::*;
 
@@ -536,4 +1329,1110 @@ mod compiler_tests {
         assert_eq!(compiler.chunk[4], Op::Func.into());
         assert_eq!(compiler.chunk[5], FuncType::Pow.into());
     }
+
+    #[test]
+    fn test_round_to() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Func(FuncType::RoundTo),
+            Token::LeftParen,
+            Token::Number(b"1.23456".as_slice().into()),
+            Token::Comma,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightParen,
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[0], Op::Number.into());
+        let (_, float) = parse_number(&compiler.chunk[eight_bytes_num(1)]);
+        assert_eq!(float, 1.23456);
+
+        assert_eq!(compiler.chunk[9], Op::NumberI8.into());
+        let float = parse_i8(compiler.chunk[10]);
+        assert_eq!(float, 2.0);
+        assert_eq!(compiler.chunk[11], Op::Func.into());
+        assert_eq!(compiler.chunk[12], FuncType::RoundTo.into());
+    }
+
+    #[test]
+    fn test_trailing_dot() {
+        let mut lexer = MockLexer::new(vec![Token::Number(b"5.".as_slice().into())]);
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[0], Op::NumberI8.into());
+        let float = parse_i8(compiler.chunk[1]);
+        assert_eq!(float, 5.0);
+    }
+
+    #[test]
+    fn test_trailing_dot_sum() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"5.".as_slice().into()),
+            Token::Plus,
+            Token::Number(b"1".as_slice().into()),
+        ]);
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[0], Op::NumberI8.into());
+        let float = parse_i8(compiler.chunk[1]);
+        assert_eq!(float, 5.0);
+        assert_eq!(compiler.chunk[2], Op::NumberI8.into());
+        let float = parse_i8(compiler.chunk[3]);
+        assert_eq!(float, 1.0);
+        assert_eq!(compiler.chunk[4], Op::Plus.into());
+    }
+
+    #[test]
+    fn test_trailing_dot_with_exponent() {
+        let mut lexer = MockLexer::new(vec![Token::Number(b"5.e2".as_slice().into())]);
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[0], Op::Number.into());
+        let (_, float) = parse_number(&compiler.chunk[eight_bytes_num(1)]);
+        assert_eq!(float, 500.0);
+    }
+
+    #[test]
+    fn test_abs_bars() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Bar,
+            Token::Minus,
+            Token::Number(b"3".as_slice().into()),
+            Token::Bar,
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[0], Op::NumberI8.into());
+        let float = parse_i8(compiler.chunk[1]);
+        assert_eq!(float, 3.0);
+        assert_eq!(compiler.chunk[2], Op::Negate.into());
+        assert_eq!(compiler.chunk[3], Op::Func.into());
+        assert_eq!(compiler.chunk[4], FuncType::Abs.into());
+    }
+
+    #[test]
+    fn test_unterminated_abs_bars() {
+        let mut lexer = MockLexer::new(vec![Token::Bar, Token::Number(b"3".as_slice().into())]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert_eq!(res, Err(Error::UnterminedAbs));
+    }
+
+    #[test]
+    fn test_bitwise_below_term() {
+        // 1 + 2 << 1 & 3
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"1".as_slice().into()),
+            Token::Plus,
+            Token::Number(b"2".as_slice().into()),
+            Token::ShiftLeft,
+            Token::Number(b"1".as_slice().into()),
+            Token::Ampersand,
+            Token::Number(b"3".as_slice().into()),
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[0], Op::NumberI8.into());
+        assert_eq!(compiler.chunk[2], Op::NumberI8.into());
+        assert_eq!(compiler.chunk[4], Op::Plus.into());
+        assert_eq!(compiler.chunk[5], Op::NumberI8.into());
+        assert_eq!(compiler.chunk[7], Op::ShiftLeft.into());
+        assert_eq!(compiler.chunk[8], Op::NumberI8.into());
+        let float = parse_i8(compiler.chunk[9]);
+        assert_eq!(float, 3.0);
+        assert_eq!(compiler.chunk[10], Op::BitAnd.into());
+    }
+
+    #[test]
+    fn test_bitwise_or_after_abs() {
+        // |-1| | 2 * 3
+        let mut lexer = MockLexer::new(vec![
+            Token::Bar,
+            Token::Minus,
+            Token::Number(b"1".as_slice().into()),
+            Token::Bar,
+            Token::Bar,
+            Token::Number(b"2".as_slice().into()),
+            Token::Mult,
+            Token::Number(b"3".as_slice().into()),
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[2], Op::Negate.into());
+        assert_eq!(compiler.chunk[3], Op::Func.into());
+        assert_eq!(compiler.chunk[4], FuncType::Abs.into());
+        assert_eq!(compiler.chunk[9], Op::Mult.into());
+        assert_eq!(compiler.chunk[10], Op::BitOr.into());
+    }
+
+    #[test]
+    fn test_bitwise_or_inside_abs_group() {
+        // |(1 | 2)|
+        let mut lexer = MockLexer::new(vec![
+            Token::Bar,
+            Token::LeftParen,
+            Token::Number(b"1".as_slice().into()),
+            Token::Bar,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightParen,
+            Token::Bar,
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.chunk[4], Op::BitOr.into());
+        assert_eq!(compiler.chunk[5], Op::Func.into());
+        assert_eq!(compiler.chunk[6], FuncType::Abs.into());
+    }
+
+    #[test]
+    fn test_ans_index() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Ans,
+            Token::LeftParen,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightParen,
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(compiler.opcodes(), &[Op::AnsN.into(), 2]);
+    }
+
+    #[test]
+    fn test_invalid_ans_index() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Ans,
+            Token::LeftParen,
+            Token::Number(b"0".as_slice().into()),
+            Token::RightParen,
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert_eq!(res, Err(Error::InvalidAnsIndex("0".to_string())));
+    }
+
+    #[test]
+    fn test_negated_power() {
+        // -2^2
+        let mut lexer = MockLexer::new(vec![
+            Token::Minus,
+            Token::Number(b"2".as_slice().into()),
+            Token::Caret,
+            Token::Number(b"2".as_slice().into()),
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                2,
+                Op::NumberI8.into(),
+                2,
+                Op::Func.into(),
+                FuncType::Pow.into(),
+                Op::Negate.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_power_of_negated_group() {
+        // (-2)^2
+        let mut lexer = MockLexer::new(vec![
+            Token::LeftParen,
+            Token::Minus,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightParen,
+            Token::Caret,
+            Token::Number(b"2".as_slice().into()),
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                2,
+                Op::Negate.into(),
+                Op::NumberI8.into(),
+                2,
+                Op::Func.into(),
+                FuncType::Pow.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_power_right_associative() {
+        // 2^3^2
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"2".as_slice().into()),
+            Token::Caret,
+            Token::Number(b"3".as_slice().into()),
+            Token::Caret,
+            Token::Number(b"2".as_slice().into()),
+        ]);
+
+        let mut compiler = Compiler::default();
+        let res = compiler.compile(&mut lexer);
+        assert!(res.is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                2,
+                Op::NumberI8.into(),
+                3,
+                Op::NumberI8.into(),
+                2,
+                Op::Func.into(),
+                FuncType::Pow.into(),
+                Op::Func.into(),
+                FuncType::Pow.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_take_opcodes() {
+        let mut compiler = Compiler::default();
+        let mut lexer = MockLexer::new(vec![Token::Number(b"1".as_slice().into())]);
+        assert!(compiler.compile(&mut lexer).is_ok());
+        let first = compiler.take_opcodes();
+        assert_eq!(first, vec![Op::NumberI8.into(), 1]);
+        assert!(compiler.opcodes().is_empty());
+
+        let mut lexer = MockLexer::new(vec![Token::Minus, Token::Number(b"2".as_slice().into())]);
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[Op::NumberI8.into(), 2, Op::Negate.into()]
+        );
+        assert_eq!(first, vec![Op::NumberI8.into(), 1]);
+    }
+
+    #[test]
+    fn test_percent() {
+        let mut lexer =
+            MockLexer::new(vec![Token::Number(b"50".as_slice().into()), Token::Percent]);
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[Op::NumberI8.into(), 50, Op::Percent.into()]
+        );
+    }
+
+    #[test]
+    fn test_percent_in_additive_context() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"100".as_slice().into()),
+            Token::Plus,
+            Token::Number(b"10".as_slice().into()),
+            Token::Percent,
+        ]);
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                100,
+                Op::NumberI8.into(),
+                10,
+                Op::Percent.into(),
+                Op::PercentOf.into(),
+                Op::Plus.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"2".as_slice().into()),
+            Token::Mult,
+            Token::Const(Constant::SpeedOfLight),
+        ]);
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                2,
+                Op::Const.into(),
+                Constant::SpeedOfLight.into(),
+                Op::Mult.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_variadic_function() {
+        // max(1, 2, 3)
+        let mut lexer = MockLexer::new(vec![
+            Token::Func(FuncType::Max),
+            Token::LeftParen,
+            Token::Number(b"1".as_slice().into()),
+            Token::Comma,
+            Token::Number(b"2".as_slice().into()),
+            Token::Comma,
+            Token::Number(b"3".as_slice().into()),
+            Token::RightParen,
+        ]);
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                1,
+                Op::NumberI8.into(),
+                2,
+                Op::NumberI8.into(),
+                3,
+                Op::Func.into(),
+                FuncType::Max.into(),
+                3,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_variadic_function_without_args() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Func(FuncType::Min),
+            Token::LeftParen,
+            Token::RightParen,
+        ]);
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile(&mut lexer),
+            Err(Error::MissingFunctionArgs(FuncType::Min))
+        );
+    }
+
+    #[test]
+    fn test_no_compact_numbers() {
+        let mut lexer = MockLexer::new(vec![Token::Number(b"1".as_slice().into())]);
+        let mut compiler = Compiler::default().with_compact_numbers(false);
+        assert!(!compiler.compact_numbers());
+        assert!(compiler.compile(&mut lexer).is_ok());
+        let opcodes = compiler.opcodes();
+        assert_eq!(opcodes.len(), 9);
+        assert_eq!(opcodes[0], Op::Number.into());
+        let (_, n) = parse_number(&opcodes[eight_bytes_num(1)]);
+        assert_eq!(n, 1.0);
+    }
+
+    #[test]
+    fn test_integer_precision_loss() {
+        let digits = b"90071992547409930".as_slice();
+
+        let mut lexer = MockLexer::new(vec![Token::Number(digits.into())]);
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.diagnostics(),
+            &[Diagnostic::IntegerPrecisionLoss(
+                "90071992547409930".to_owned()
+            )]
+        );
+        compiler.reset();
+        assert!(compiler.diagnostics().is_empty());
+
+        let mut lexer = MockLexer::new(vec![Token::Number(digits.into())]);
+        let mut compiler = Compiler::default().with_strict_integers(true);
+        assert_eq!(
+            compiler.compile(&mut lexer),
+            Err(Error::IntegerPrecisionLoss("90071992547409930".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_exact_integer_literals() {
+        assert!(!loses_integer_precision(b"9007199254740992"));
+        assert!(loses_integer_precision(b"9007199254740993"));
+        assert!(!loses_integer_precision(b"9007199254740993.0"));
+        assert!(!loses_integer_precision(b"1e20"));
+    }
+
+    #[test]
+    fn test_compile_collecting() {
+        // (1 + ) * 2 + sin 3
+        let mut lexer = MockLexer::new(vec![
+            Token::LeftParen,
+            Token::Number(b"1".as_slice().into()),
+            Token::Plus,
+            Token::RightParen,
+            Token::Mult,
+            Token::Number(b"2".as_slice().into()),
+            Token::Plus,
+            Token::Func(FuncType::Sin),
+            Token::Number(b"3".as_slice().into()),
+        ]);
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile_collecting(&mut lexer),
+            Err(vec![
+                (
+                    None,
+                    Error::InvalidTokenBefore {
+                        prev: ")".to_owned(),
+                        current: Some("*".to_owned())
+                    }
+                ),
+                (None, Error::MissingFunctionParen),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_compile_collecting_statements() {
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile_collecting(&mut Lexer::new(b"1+;2*")),
+            Err(vec![
+                (
+                    Some(Span { start: 1, end: 2 }),
+                    Error::InvalidTokenBefore {
+                        prev: "+".to_owned(),
+                        current: Some(";".to_owned())
+                    }
+                ),
+                (
+                    Some(Span { start: 4, end: 5 }),
+                    Error::InvalidTokenBefore {
+                        prev: "*".to_owned(),
+                        current: None
+                    }
+                ),
+            ])
+        );
+
+        compiler.reset();
+        assert_eq!(
+            compiler.compile_collecting(&mut Lexer::new(b"(1 @ 2); 3 + )")),
+            Err(vec![
+                (
+                    Some(Span { start: 3, end: 4 }),
+                    Error::FromLexer(LexerError::InvalidChar('@'))
+                ),
+                (
+                    Some(Span { start: 13, end: 14 }),
+                    Error::InvalidTokenBefore {
+                        prev: ")".to_owned(),
+                        current: None
+                    }
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_compile_collecting_without_errors() {
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"1".as_slice().into()),
+            Token::Plus,
+            Token::Number(b"2".as_slice().into()),
+        ]);
+        let mut compiler = Compiler::default();
+        assert_eq!(compiler.compile_collecting(&mut lexer), Ok(()));
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                1,
+                Op::NumberI8.into(),
+                2,
+                Op::Plus.into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_argument() {
+        // pow(2)
+        let mut lexer = MockLexer::new(vec![
+            Token::Func(FuncType::Pow),
+            Token::LeftParen,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightParen,
+        ]);
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile(&mut lexer),
+            Err(Error::WrongArity {
+                func: "pow".to_owned(),
+                expected: 2,
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_extra_argument() {
+        // sin(1, 2)
+        let mut lexer = MockLexer::new(vec![
+            Token::Func(FuncType::Sin),
+            Token::LeftParen,
+            Token::Number(b"1".as_slice().into()),
+            Token::Comma,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightParen,
+        ]);
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile(&mut lexer),
+            Err(Error::WrongArity {
+                func: "sin".to_owned(),
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_comma() {
+        // pow(2 3)
+        let mut lexer = MockLexer::new(vec![
+            Token::Func(FuncType::Pow),
+            Token::LeftParen,
+            Token::Number(b"2".as_slice().into()),
+            Token::Number(b"3".as_slice().into()),
+            Token::RightParen,
+        ]);
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile(&mut lexer),
+            Err(Error::MissingCommaInFunctionCall)
+        );
+    }
+
+    #[test]
+    fn test_too_many_tokens() {
+        // 1 + 1 + ... + 1
+        let mut tokens = vec![Token::Number(b"1".as_slice().into())];
+        for _ in 0..100 {
+            tokens.push(Token::Plus);
+            tokens.push(Token::Number(b"1".as_slice().into()));
+        }
+
+        let mut compiler = Compiler::default().with_max_tokens(Some(200));
+        assert_eq!(compiler.max_tokens(), Some(200));
+        let mut lexer = MockLexer::new(tokens.clone());
+        assert_eq!(compiler.compile(&mut lexer), Err(Error::TooManyTokens(200)));
+
+        compiler.reset();
+        let mut compiler = compiler.with_max_tokens(Some(201));
+        let mut lexer = MockLexer::new(tokens);
+        assert_eq!(compiler.compile(&mut lexer), Ok(()));
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            Error::FromLexer(LexerError::Eof),
+            Error::InvalidNumber(b"1..2".to_vec()),
+            Error::InvalidTokenBefore {
+                prev: "+".into(),
+                current: None,
+            },
+            Error::UnterminedGroup,
+            Error::UnterminedAbs,
+            Error::InvalidAnsIndex("0".into()),
+            Error::InvalidToken(")".into()),
+            Error::MissingFunctionParen,
+            Error::MissingCommaInFunctionCall,
+            Error::MissingFunctionArgs(FuncType::Min),
+            Error::TooManyFunctionArgs(FuncType::Max),
+            Error::IntegerPrecisionLoss("9007199254740993".into()),
+            Error::WrongArity {
+                func: "pow".into(),
+                expected: 2,
+                found: 1,
+            },
+            Error::TooManyTokens(10),
+            Error::TooManyExternArgs("f".into()),
+            Error::EmptyExpression,
+            Error::AnsNotAllowed,
+            Error::ExpectedOperand {
+                before: "*".into(),
+                offset: None,
+            },
+        ];
+        let codes: std::collections::HashSet<_> = errors.iter().map(Error::code).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert_eq!(Error::UnterminedGroup.code(), "E_UNTERMINATED_GROUP");
+        assert_eq!(Error::FromLexer(LexerError::Eof).code(), "E_EOF");
+    }
+
+    #[test]
+    fn test_i8_boundaries() {
+        let compiled = |src: &str| Compiler::compile_once(src).unwrap();
+        assert_eq!(compiled("127"), vec![Op::NumberI8.into(), 127]);
+        let mut f64_128 = vec![Op::Number.into()];
+        f64_128.extend_from_slice(&write_f64_operand(128.0));
+        assert_eq!(compiled("128"), f64_128);
+        // a literal has no sign: `-128` is `128` negated, too large for an i8 operand
+        f64_128.push(Op::Negate.into());
+        assert_eq!(compiled("-128"), f64_128);
+        assert_eq!(
+            compiled("-127"),
+            vec![Op::NumberI8.into(), 127, Op::Negate.into()]
+        );
+    }
+
+    #[test]
+    fn test_uses_ans() {
+        let mut compiler = Compiler::default();
+        let mut lexer = MockLexer::new(vec![
+            Token::Ans,
+            Token::Plus,
+            Token::Number(b"1".as_slice().into()),
+        ]);
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert!(compiler.uses_ans());
+        compiler.reset();
+        assert!(!compiler.uses_ans());
+
+        let mut lexer = MockLexer::new(vec![
+            Token::Number(b"1".as_slice().into()),
+            Token::Plus,
+            Token::Number(b"2".as_slice().into()),
+        ]);
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert!(!compiler.uses_ans());
+    }
+
+    #[test]
+    fn test_bracket_group() {
+        let mut lexer = MockLexer::new(vec![
+            Token::LeftBracket,
+            Token::Number(b"1".as_slice().into()),
+            Token::Plus,
+            Token::Number(b"2".as_slice().into()),
+            Token::RightBracket,
+            Token::Mult,
+            Token::Number(b"3".as_slice().into()),
+        ]);
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                1,
+                Op::NumberI8.into(),
+                2,
+                Op::Plus.into(),
+                Op::NumberI8.into(),
+                3,
+                Op::Mult.into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mismatched_brackets() {
+        for (open, close) in [
+            (Token::LeftParen, Token::RightBracket),
+            (Token::LeftBracket, Token::RightParen),
+        ] {
+            let mut lexer =
+                MockLexer::new(vec![open, Token::Number(b"1".as_slice().into()), close]);
+            let mut compiler = Compiler::default();
+            assert_eq!(compiler.compile(&mut lexer), Err(Error::UnterminedGroup));
+        }
+    }
+
+    #[test]
+    fn test_op_mnemonics() {
+        let ops: Vec<Op> = (0..=u8::MAX).filter_map(|b| Op::try_from(b).ok()).collect();
+        let mnemonics: std::collections::HashSet<_> = ops.iter().map(Op::mnemonic).collect();
+        assert_eq!(mnemonics.len(), ops.len());
+        assert!(mnemonics.iter().all(|m| !m.is_empty()));
+        assert_eq!(Op::Plus.to_string(), "ADD");
+        assert_eq!(Op::NumberI8.to_string(), "PUSH_I8");
+    }
+
+    #[test]
+    fn test_trailing_halt() {
+        let tokens = || {
+            MockLexer::new(vec![
+                Token::Number(b"1".as_slice().into()),
+                Token::Plus,
+                Token::Number(b"2".as_slice().into()),
+            ])
+        };
+        let mut compiler = Compiler::default().with_halt(true);
+        assert!(compiler.halt());
+        assert!(compiler.compile(&mut tokens()).is_ok());
+        assert_eq!(compiler.opcodes().last(), Some(&Op::Halt.into()));
+        compiler.reset();
+        assert!(compiler.compile_collecting(&mut tokens()).is_ok());
+        assert_eq!(compiler.opcodes().last(), Some(&Op::Halt.into()));
+
+        let mut compiler = Compiler::default();
+        assert!(compiler.compile(&mut tokens()).is_ok());
+        assert_eq!(compiler.opcodes().last(), Some(&Op::Plus.into()));
+    }
+
+    #[test]
+    fn test_empty_expression() {
+        let mut compiler = Compiler::default();
+        assert_eq!(
+            compiler.compile(&mut MockLexer::new(vec![])),
+            Err(Error::EmptyExpression)
+        );
+        assert_eq!(
+            compiler.compile_collecting(&mut MockLexer::new(vec![])),
+            Err(vec![(None, Error::EmptyExpression)])
+        );
+    }
+
+    #[test]
+    fn test_compile_once() {
+        let first = Compiler::compile_once("1 + 2").unwrap();
+        let second = Compiler::compile_once("3").unwrap();
+        assert_eq!(second, vec![Op::NumberI8.into(), 3]);
+        assert_eq!(first, Compiler::compile_once("1 + 2").unwrap());
+        assert_eq!(Compiler::compile_once(""), Err(Error::EmptyExpression));
+    }
+
+    #[test]
+    fn test_f64_operand_round_trip() {
+        let values = [
+            0.0,
+            -0.0,
+            1.5,
+            -1234.5678,
+            f64::MIN_POSITIVE / 2.0,
+            -f64::MIN_POSITIVE / 4.0,
+            f64::MAX,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for n in values {
+            let bytes = write_f64_operand(n);
+            assert_eq!(read_f64_operand(&bytes).to_bits(), n.to_bits());
+        }
+        assert_eq!(write_f64_operand(1.0), [0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+    }
+
+    #[test]
+    fn test_angle_suffix() {
+        let chunk = Compiler::compile_once("90deg").unwrap();
+        assert_eq!(chunk, [Op::NumberI8.into(), 90, Op::Degrees.into()]);
+        let chunk = Compiler::compile_once("1.5rad").unwrap();
+        assert_eq!(chunk[0], Op::Number.into());
+        assert_eq!(read_f64_operand(chunk[1..9].try_into().unwrap()), 1.5);
+        assert_eq!(chunk[9..], [Op::Radians.into()]);
+    }
+
+    #[test]
+    fn test_extern_call() {
+        let names = ["hyp"];
+        let mut lexer = Lexer::new(b"hyp(3, 4)").with_extern_names(&names);
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut lexer).unwrap();
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                3,
+                Op::NumberI8.into(),
+                4,
+                Op::CallExtern.into(),
+                2,
+                3,
+                b'h',
+                b'y',
+                b'p'
+            ]
+        );
+    }
+
+    #[test]
+    fn test_percentage_functions() {
+        for func in [FuncType::PctChange, FuncType::PctOf] {
+            let src = func.name().to_owned() + "(1, 4)";
+            assert_eq!(
+                Compiler::compile_once(&src),
+                Ok(vec![
+                    Op::NumberI8.into(),
+                    1,
+                    Op::NumberI8.into(),
+                    4,
+                    Op::Func.into(),
+                    func.into()
+                ])
+            );
+        }
+    }
+
+    #[test]
+    fn test_comments_skipped() {
+        let mut lexer = Lexer::new(b"# sum\n1 + # of two\n2 # done").with_comments(true);
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut lexer).unwrap();
+        assert_eq!(
+            compiler.opcodes(),
+            &[
+                Op::NumberI8.into(),
+                1,
+                Op::NumberI8.into(),
+                2,
+                Op::Plus.into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        let depth = |src: &str| {
+            let mut compiler = Compiler::default();
+            compiler.compile(&mut Lexer::new(src.as_bytes())).unwrap();
+            compiler.max_stack_depth()
+        };
+        assert_eq!(depth("1"), 1);
+        assert_eq!(depth("1 + 2 + 3"), 2);
+        assert_eq!(depth("1 + (2 + (3 + 4))"), 4);
+        assert_eq!(depth("max(1, 2, 3) * 2"), 3);
+        assert_eq!(Compiler::default().max_stack_depth(), 0);
+    }
+
+    #[test]
+    fn test_non_finite_literals() {
+        for literal in ["inf", "nan"] {
+            assert_eq!(
+                Compiler::compile_once(&("2 * ".to_owned() + literal)),
+                Err(Error::FromLexer(LexerError::UnsupportedLiteral(literal)))
+            );
+        }
+    }
+
+    #[test]
+    fn test_forbid_ans() {
+        let compile = |src: &[u8]| {
+            let mut compiler = Compiler::default().with_forbid_ans(true);
+            compiler.compile(&mut Lexer::new(src))
+        };
+        assert_eq!(compile(b"ans + 1"), Err(Error::AnsNotAllowed));
+        assert_eq!(compile(b"2 * ans(1)"), Err(Error::AnsNotAllowed));
+        assert_eq!(compile(b"1 + 1"), Ok(()));
+    }
+
+    #[test]
+    fn test_compile_into() {
+        let mut compiler = Compiler::default();
+        let mut arena = Vec::new();
+        compiler
+            .compile_into(&mut Lexer::new(b"1 + 2"), &mut arena)
+            .unwrap();
+        let first_len = arena.len();
+        compiler
+            .compile_into(&mut Lexer::new(b"3"), &mut arena)
+            .unwrap();
+        assert_eq!(
+            arena,
+            [
+                Op::NumberI8.into(),
+                1,
+                Op::NumberI8.into(),
+                2,
+                Op::Plus.into(),
+                Op::NumberI8.into(),
+                3
+            ]
+        );
+        assert_eq!(first_len, 5);
+        assert!(compiler.opcodes().is_empty());
+
+        let res = compiler.compile_into(&mut Lexer::new(b"4 * (5"), &mut arena);
+        assert_eq!(res, Err(Error::UnterminedGroup));
+        assert_eq!(arena.len(), 7);
+    }
+
+    #[test]
+    fn test_redundant_parens() {
+        let lint = |src: &[u8]| {
+            let mut compiler = Compiler::default().with_lint_parens(true);
+            compiler.compile(&mut Lexer::new(src)).unwrap();
+            compiler.diagnostics().to_vec()
+        };
+        let redundant = |start, end| Diagnostic::RedundantParens(Some(Span { start, end }));
+        assert_eq!(lint(b"(3)"), vec![redundant(0, 3)]);
+        assert_eq!(lint(b"1 + ((1+2))"), vec![redundant(4, 11)]);
+        assert_eq!(lint(b"2*(1+3)"), vec![]);
+        assert_eq!(lint(b"2^(-3)"), vec![]);
+        assert_eq!(lint(b"200 + (10%)"), vec![]);
+
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut Lexer::new(b"(3)")).unwrap();
+        assert!(compiler.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_expected_operand() {
+        let compile = |src: &[u8]| Compiler::default().compile(&mut Lexer::new(src));
+        let expected = |before: &str, offset| {
+            Err(Error::ExpectedOperand {
+                before: before.into(),
+                offset: Some(offset),
+            })
+        };
+        assert_eq!(compile(b"*"), expected("*", 0));
+        assert_eq!(compile(b"/ 2"), expected("/", 0));
+        assert_eq!(compile(b"+ )"), expected("+", 0));
+        assert_eq!(compile(b"1 + (% 2)"), expected("%", 5));
+        assert_eq!(compile(b"-1"), Ok(()));
+
+        let mut lexer = MockLexer::new(vec![Token::Mult, Token::Number(b"2".as_slice().into())]);
+        assert_eq!(
+            Compiler::default().compile(&mut lexer),
+            Err(Error::ExpectedOperand {
+                before: "*".into(),
+                offset: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_fma_arguments() {
+        assert_eq!(
+            Compiler::compile_once("fma(1, 2, 3)"),
+            Ok(vec![
+                Op::NumberI8.into(),
+                1,
+                Op::NumberI8.into(),
+                2,
+                Op::NumberI8.into(),
+                3,
+                Op::Func.into(),
+                FuncType::Fma.into()
+            ])
+        );
+        assert_eq!(
+            Compiler::compile_once("fma(1, 2)"),
+            Err(Error::WrongArity {
+                func: "fma".into(),
+                expected: 3,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            Error::FromLexer(LexerError::InvalidChar('@')).to_string(),
+            "invalid character '@'"
+        );
+        assert_eq!(
+            Error::InvalidTokenBefore {
+                prev: ")".into(),
+                current: Some("*".into()),
+            }
+            .to_string(),
+            "unexpected token ')' before '*'"
+        );
+        assert_eq!(
+            Error::WrongArity {
+                func: "max".into(),
+                expected: 2,
+                found: 1,
+            }
+            .to_string(),
+            "max takes 2 argument(s), found 1"
+        );
+        assert_eq!(
+            Error::IntegerPrecisionLoss("90071992547409930".into()).to_string(),
+            "integer literal '90071992547409930' cannot be represented exactly"
+        );
+        assert_eq!(
+            Error::ExpectedOperand {
+                before: "*".into(),
+                offset: Some(0),
+            }
+            .to_string(),
+            "expected an operand before '*' at offset 0"
+        );
+        assert_eq!(
+            Error::ExpectedOperand {
+                before: "*".into(),
+                offset: None,
+            }
+            .to_string(),
+            "expected an operand before '*'"
+        );
+    }
+
+    #[test]
+    fn test_semicolon_separated() {
+        let mut compiler = Compiler::default();
+        let mut lexer = Lexer::new(b"1; 2;");
+        assert!(compiler.compile(&mut lexer).is_ok());
+        assert_eq!(
+            compiler.opcodes(),
+            [
+                Op::NumberI8.into(),
+                1,
+                Op::SetAns.into(),
+                Op::NumberI8.into(),
+                2
+            ]
+        );
+        assert_eq!(compiler.max_stack_depth(), 1);
+
+        compiler.reset();
+        assert_eq!(
+            compiler.compile(&mut Lexer::new(b"1;;2")),
+            Err(Error::ExpectedOperand {
+                before: ";".into(),
+                offset: Some(2),
+            })
+        );
+
+        compiler.reset();
+        assert_eq!(
+            compiler.compile(&mut Lexer::new(b";")),
+            Err(Error::ExpectedOperand {
+                before: ";".into(),
+                offset: Some(0),
+            })
+        );
+        compiler.reset();
+        assert_eq!(
+            compiler.compile_collecting(&mut Lexer::new(b";")),
+            Err(vec![(
+                Some(Span { start: 0, end: 1 }),
+                Error::ExpectedOperand {
+                    before: ";".into(),
+                    offset: Some(0),
+                }
+            )])
+        );
+    }
 }
@@ -1,3 +1,8 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum FuncType {
@@ -6,26 +11,206 @@ pub enum FuncType {
     Sin,
     Cos,
     Pow,
+    RoundTo,
+    Abs,
+    Min,
+    Max,
+    Sec,
+    Csc,
+    Cot,
+    IDiv,
+    Ssqrt,
+    Tau,
+    Phi,
+    Rand,
+    Step,
+    Rect,
+    PctChange,
+    PctOf,
+    Sinc,
+    Saturate,
+    Fma,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Sinh,
+    Cosh,
+    Tanh,
+    Gcd,
 }
 
 impl FuncType {
+    /// For variadic functions this is the minimum number of arguments.
     pub fn arity(&self) -> usize {
         match self {
-            Self::Pow => 2,
-            Self::Sqrt | Self::Log | Self::Cos | Self::Sin => 1,
+            Self::Tau | Self::Phi | Self::Rand => 0,
+            Self::Fma => 3,
+            Self::Pow
+            | Self::RoundTo
+            | Self::IDiv
+            | Self::PctChange
+            | Self::PctOf
+            | Self::Atan2
+            | Self::Gcd => 2,
+            Self::Sqrt
+            | Self::Log
+            | Self::Cos
+            | Self::Sin
+            | Self::Abs
+            | Self::Min
+            | Self::Max
+            | Self::Sec
+            | Self::Csc
+            | Self::Cot
+            | Self::Ssqrt
+            | Self::Step
+            | Self::Rect
+            | Self::Sinc
+            | Self::Saturate
+            | Self::Asin
+            | Self::Acos
+            | Self::Atan
+            | Self::Sinh
+            | Self::Cosh
+            | Self::Tanh => 1,
+        }
+    }
+
+    /// Variadic functions are followed in the bytecode by their argument count.
+    pub fn is_variadic(&self) -> bool {
+        matches!(self, Self::Min | Self::Max)
+    }
+
+    /// Every function, in opcode order.
+    pub fn all() -> impl Iterator<Item = FuncType> {
+        [
+            Self::Sqrt,
+            Self::Log,
+            Self::Sin,
+            Self::Cos,
+            Self::Pow,
+            Self::RoundTo,
+            Self::Abs,
+            Self::Min,
+            Self::Max,
+            Self::Sec,
+            Self::Csc,
+            Self::Cot,
+            Self::IDiv,
+            Self::Ssqrt,
+            Self::Tau,
+            Self::Phi,
+            Self::Rand,
+            Self::Step,
+            Self::Rect,
+            Self::PctChange,
+            Self::PctOf,
+            Self::Sinc,
+            Self::Saturate,
+            Self::Fma,
+            Self::Asin,
+            Self::Acos,
+            Self::Atan,
+            Self::Atan2,
+            Self::Sinh,
+            Self::Cosh,
+            Self::Tanh,
+            Self::Gcd,
+        ]
+        .into_iter()
+    }
+
+    /// The name used to call the function in an expression.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Log => "log",
+            Self::Sin => "sin",
+            Self::Cos => "cos",
+            Self::Sqrt => "sqrt",
+            Self::Pow => "pow",
+            Self::RoundTo => "round_to",
+            Self::Abs => "abs",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Sec => "sec",
+            Self::Csc => "csc",
+            Self::Cot => "cot",
+            Self::IDiv => "idiv",
+            Self::Ssqrt => "ssqrt",
+            Self::Tau => "tau",
+            Self::Phi => "phi",
+            Self::Rand => "rand",
+            Self::Step => "step",
+            Self::Rect => "rect",
+            Self::PctChange => "pctchange",
+            Self::PctOf => "pctof",
+            Self::Sinc => "sinc",
+            Self::Saturate => "saturate",
+            Self::Fma => "fma",
+            Self::Asin => "asin",
+            Self::Acos => "acos",
+            Self::Atan => "atan",
+            Self::Atan2 => "atan2",
+            Self::Sinh => "sinh",
+            Self::Cosh => "cosh",
+            Self::Tanh => "tanh",
+            Self::Gcd => "gcd",
+        }
+    }
+
+    /// Bounds of the first argument. An endpoint may itself be outside the
+    /// domain, as `0` is for `log`; see `description`.
+    pub fn domain(&self) -> (f64, f64) {
+        match self {
+            Self::Sqrt | Self::Log => (0.0, f64::INFINITY),
+            Self::Asin | Self::Acos => (-1.0, 1.0),
+            _ => (f64::NEG_INFINITY, f64::INFINITY),
+        }
+    }
+
+    /// One line usage and domain, e.g. for a help command.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Sqrt => "sqrt(x): x ≥ 0",
+            Self::Log => "log(x): natural logarithm, x > 0",
+            Self::Sin => "sin(x): sine",
+            Self::Cos => "cos(x): cosine",
+            Self::Pow => "pow(x, y): x to the y, y integer if x < 0",
+            Self::RoundTo => "round_to(x, n): x rounded to n decimals, n integer clamped to 0..=15",
+            Self::Abs => "abs(x): absolute value",
+            Self::Min => "min(x, ...): smallest argument",
+            Self::Max => "max(x, ...): largest argument",
+            Self::Sec => "sec(x): 1 / cos(x), cos(x) ≠ 0",
+            Self::Csc => "csc(x): 1 / sin(x), sin(x) ≠ 0",
+            Self::Cot => "cot(x): cos(x) / sin(x), sin(x) ≠ 0",
+            Self::IDiv => "idiv(x, y): x / y truncated, y ≠ 0",
+            Self::Ssqrt => "ssqrt(x): sign preserving square root",
+            Self::Tau => "tau(): 2π",
+            Self::Phi => "phi(): golden ratio",
+            Self::Rand => "rand(): uniform random number in [0, 1)",
+            Self::Step => "step(x): 0 if x < 0, 1 otherwise",
+            Self::Rect => "rect(x): 1 if |x| ≤ 0.5, 0 otherwise",
+            Self::PctChange => "pctchange(old, new): change from old to new in percent, old ≠ 0",
+            Self::PctOf => "pctof(part, whole): part as a percentage of whole, whole ≠ 0",
+            Self::Sinc => "sinc(x): sin(x) / x, unnormalized, 1 at 0",
+            Self::Saturate => "saturate(x): x clamped to [0, 1]",
+            Self::Fma => "fma(a, b, c): a * b + c with a single rounding",
+            Self::Asin => "asin(x): inverse sine, -1 ≤ x ≤ 1",
+            Self::Acos => "acos(x): inverse cosine, -1 ≤ x ≤ 1",
+            Self::Atan => "atan(x): inverse tangent",
+            Self::Atan2 => "atan2(y, x): angle of the point (x, y)",
+            Self::Sinh => "sinh(x): hyperbolic sine",
+            Self::Cosh => "cosh(x): hyperbolic cosine",
+            Self::Tanh => "tanh(x): hyperbolic tangent",
+            Self::Gcd => "gcd(a, b): greatest common divisor, a and b integers",
         }
     }
 }
 
 impl From<FuncType> for String {
     fn from(value: FuncType) -> Self {
-        match value {
-            FuncType::Log => "Log".into(),
-            FuncType::Sin => "sin".into(),
-            FuncType::Cos => "cos".into(),
-            FuncType::Sqrt => "sqrt".into(),
-            FuncType::Pow => "pow".into(),
-        }
+        value.name().into()
     }
 }
 
@@ -36,7 +221,7 @@ impl From<FuncType> for u8 {
 }
 
 #[derive(Debug)]
-pub struct InvalidFuncCode(u8);
+pub struct InvalidFuncCode(pub u8);
 
 impl TryFrom<u8> for FuncType {
     type Error = InvalidFuncCode;
@@ -47,11 +232,99 @@ impl TryFrom<u8> for FuncType {
             x if FuncType::Sin as u8 == x => Ok(FuncType::Sin),
             x if FuncType::Cos as u8 == x => Ok(FuncType::Cos),
             x if FuncType::Pow as u8 == x => Ok(FuncType::Pow),
+            x if FuncType::RoundTo as u8 == x => Ok(FuncType::RoundTo),
+            x if FuncType::Abs as u8 == x => Ok(FuncType::Abs),
+            x if FuncType::Min as u8 == x => Ok(FuncType::Min),
+            x if FuncType::Max as u8 == x => Ok(FuncType::Max),
+            x if FuncType::Sec as u8 == x => Ok(FuncType::Sec),
+            x if FuncType::Csc as u8 == x => Ok(FuncType::Csc),
+            x if FuncType::Cot as u8 == x => Ok(FuncType::Cot),
+            x if FuncType::IDiv as u8 == x => Ok(FuncType::IDiv),
+            x if FuncType::Ssqrt as u8 == x => Ok(FuncType::Ssqrt),
+            x if FuncType::Tau as u8 == x => Ok(FuncType::Tau),
+            x if FuncType::Phi as u8 == x => Ok(FuncType::Phi),
+            x if FuncType::Rand as u8 == x => Ok(FuncType::Rand),
+            x if FuncType::Step as u8 == x => Ok(FuncType::Step),
+            x if FuncType::Rect as u8 == x => Ok(FuncType::Rect),
+            x if FuncType::PctChange as u8 == x => Ok(FuncType::PctChange),
+            x if FuncType::PctOf as u8 == x => Ok(FuncType::PctOf),
+            x if FuncType::Sinc as u8 == x => Ok(FuncType::Sinc),
+            x if FuncType::Saturate as u8 == x => Ok(FuncType::Saturate),
+            x if FuncType::Fma as u8 == x => Ok(FuncType::Fma),
+            x if FuncType::Asin as u8 == x => Ok(FuncType::Asin),
+            x if FuncType::Acos as u8 == x => Ok(FuncType::Acos),
+            x if FuncType::Atan as u8 == x => Ok(FuncType::Atan),
+            x if FuncType::Atan2 as u8 == x => Ok(FuncType::Atan2),
+            x if FuncType::Sinh as u8 == x => Ok(FuncType::Sinh),
+            x if FuncType::Cosh as u8 == x => Ok(FuncType::Cosh),
+            x if FuncType::Tanh as u8 == x => Ok(FuncType::Tanh),
+            x if FuncType::Gcd as u8 == x => Ok(FuncType::Gcd),
             x => Err(InvalidFuncCode(x)),
         }
     }
 }
 
+/// Named physical constants, in SI units. The exact values are:
+///
+/// - `c`: speed of light in vacuum, 299792458 m/s
+/// - `g`: standard gravity, 9.80665 m/s²
+/// - `h`: Planck constant, 6.62607015e-34 J·s
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Constant {
+    SpeedOfLight,
+    Gravity,
+    Planck,
+}
+
+impl Constant {
+    pub fn value(&self) -> f64 {
+        match self {
+            Self::SpeedOfLight => 299_792_458.0,
+            Self::Gravity => 9.80665,
+            Self::Planck => 6.62607015e-34,
+        }
+    }
+}
+
+impl From<Constant> for String {
+    fn from(value: Constant) -> Self {
+        match value {
+            Constant::SpeedOfLight => "c".into(),
+            Constant::Gravity => "g".into(),
+            Constant::Planck => "h".into(),
+        }
+    }
+}
+
+impl From<Constant> for u8 {
+    fn from(value: Constant) -> Self {
+        value as u8
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidConstantCode(pub u8);
+
+impl TryFrom<u8> for Constant {
+    type Error = InvalidConstantCode;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if Constant::SpeedOfLight as u8 == x => Ok(Constant::SpeedOfLight),
+            x if Constant::Gravity as u8 == x => Ok(Constant::Gravity),
+            x if Constant::Planck as u8 == x => Ok(Constant::Planck),
+            x => Err(InvalidConstantCode(x)),
+        }
+    }
+}
+
+#[cfg(feature = "constants")]
+const CONSTANTS: &[(&[u8], Constant)] = &[
+    (b"c", Constant::SpeedOfLight),
+    (b"g", Constant::Gravity),
+    (b"h", Constant::Planck),
+];
+
 #[derive(Copy, Clone)]
 pub struct UnsafeSlice {
     bytes: *const u8,
@@ -68,8 +341,8 @@ impl PartialEq for UnsafeSlice {
 
 impl Eq for UnsafeSlice {}
 
-impl std::fmt::Debug for UnsafeSlice {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for UnsafeSlice {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let slice: &[u8] = (*self).into();
         write!(f, "{:?}", slice)
     }
@@ -86,7 +359,7 @@ impl From<&[u8]> for UnsafeSlice {
 
 impl From<UnsafeSlice> for &[u8] {
     fn from(UnsafeSlice { bytes, count }: UnsafeSlice) -> Self {
-        unsafe { std::slice::from_raw_parts(bytes, count) }
+        unsafe { core::slice::from_raw_parts(bytes, count) }
     }
 }
 
@@ -95,6 +368,9 @@ pub enum Token {
     Number(UnsafeSlice),
     LeftParen,
     RightParen,
+    /// `[` and `]` group like parentheses, but cannot call functions.
+    LeftBracket,
+    RightBracket,
     Plus,
     Minus,
     Mult,
@@ -102,6 +378,20 @@ pub enum Token {
     Func(FuncType),
     Comma,
     Ans,
+    Bar,
+    Ampersand,
+    ShiftLeft,
+    ShiftRight,
+    Caret,
+    Percent,
+    Const(Constant),
+    /// The name of a function registered with `Lexer::with_extern_names`.
+    Ident(UnsafeSlice),
+    /// A `#` and the rest of its line, scanned with `Lexer::with_comments`.
+    Comment(UnsafeSlice),
+    /// Separates expressions. With `DecimalSeparator::Comma` a `;` separates
+    /// function arguments instead.
+    Semicolon,
 }
 
 impl From<Token> for String {
@@ -114,21 +404,41 @@ impl From<Token> for String {
             Token::Minus => "-".to_string(),
             Token::LeftParen => "(".to_string(),
             Token::RightParen => ")".to_string(),
+            Token::LeftBracket => "[".to_string(),
+            Token::RightBracket => "]".to_string(),
             Token::Func(f) => f.into(),
             Token::Comma => ",".to_string(),
             Token::Ans => "ans".to_string(),
+            Token::Bar => "|".to_string(),
+            Token::Ampersand => "&".to_string(),
+            Token::ShiftLeft => "<<".to_string(),
+            Token::ShiftRight => ">>".to_string(),
+            Token::Caret => "^".to_string(),
+            Token::Percent => "%".to_string(),
+            Token::Const(c) => c.into(),
+            Token::Ident(name) => String::from_utf8_lossy(name.into()).into_owned(),
+            Token::Comment(text) => String::from_utf8_lossy(text.into()).into_owned(),
+            Token::Semicolon => ";".to_string(),
         }
     }
 }
 
+/// Binding power of a token, from loosest to tightest. The derived ordering
+/// follows the declaration order, so `Priority::Term < Priority::Factor`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Priority {
     Null,
     Comma,
     Number,
+    /// `&`, `|`, `<<` and `>>` share a single level below `+` and `-`,
+    /// so `1 + 2 << 1` is `(1 + 2) << 1`.
+    Bitwise,
     Term,
     Factor,
     Unary,
+    /// Binds tighter than unary minus: `-2^2` is `-(2^2)`.
+    Power,
+    Postfix,
     Group,
 }
 
@@ -137,13 +447,37 @@ impl Priority {
         match self {
             Self::Null => Self::Comma,
             Self::Comma => Self::Number,
-            Self::Number => Self::Term,
+            Self::Number => Self::Bitwise,
+            Self::Bitwise => Self::Term,
             Self::Term => Self::Factor,
             Self::Factor => Self::Unary,
-            Self::Unary => Self::Group,
+            Self::Unary => Self::Power,
+            Self::Power => Self::Postfix,
+            Self::Postfix => Self::Group,
             Self::Group => Self::Group,
         }
     }
+
+    /// The inverse of `next`, saturating at `Null`.
+    pub fn prev(&self) -> Self {
+        match self {
+            Self::Null => Self::Null,
+            Self::Comma => Self::Null,
+            Self::Number => Self::Comma,
+            Self::Bitwise => Self::Number,
+            Self::Term => Self::Bitwise,
+            Self::Factor => Self::Term,
+            Self::Unary => Self::Factor,
+            Self::Power => Self::Unary,
+            Self::Postfix => Self::Power,
+            Self::Group => Self::Postfix,
+        }
+    }
+}
+
+/// The precedence the compiler gives `token`, for tools reusing its rules.
+pub fn priority_of(token: &Token) -> Priority {
+    token.priority()
 }
 
 impl Token {
@@ -151,9 +485,20 @@ impl Token {
         match self {
             Token::Ans => Priority::Number,
             Token::Number(_) => Priority::Number,
-            Token::Func(_) => Priority::Factor,
+            Token::Const(_) => Priority::Number,
+            Token::Func(_) | Token::Ident(_) => Priority::Factor,
             Token::LeftParen => Priority::Group,
             Token::RightParen => Priority::Null,
+            Token::Comment(_) => Priority::Null,
+            Token::Semicolon => Priority::Null,
+            Token::LeftBracket => Priority::Group,
+            Token::RightBracket => Priority::Null,
+            Token::Bar => Priority::Bitwise,
+            Token::Ampersand => Priority::Bitwise,
+            Token::ShiftLeft => Priority::Bitwise,
+            Token::ShiftRight => Priority::Bitwise,
+            Token::Caret => Priority::Power,
+            Token::Percent => Priority::Postfix,
             Token::Plus => Priority::Term,
             Token::Minus => Priority::Term,
             Token::Mult => Priority::Factor,
@@ -168,28 +513,172 @@ pub enum Error {
     Eof,
     InvalidChar(char),
     InvalidNumberFormat(char),
+    /// A number literal starting at `offset` is longer than the lexer's limit.
+    NumberTooLong {
+        offset: usize,
+    },
+    /// `inf` or `nan`, which are not accepted as numbers.
+    UnsupportedLiteral(&'static str),
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+impl Error {
+    /// Stable machine-readable identifier of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Eof => "E_EOF",
+            Self::InvalidChar(_) => "E_INVALID_CHAR",
+            Self::InvalidNumberFormat(_) => "E_INVALID_NUMBER_FORMAT",
+            Self::NumberTooLong { .. } => "E_NUMBER_TOO_LONG",
+            Self::UnsupportedLiteral(_) => "E_UNSUPPORTED_LITERAL",
+        }
     }
 }
 
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Eof => f.write_str("unexpected end of input"),
+            Self::InvalidChar(c) => write!(f, "invalid character '{}'", c),
+            Self::InvalidNumberFormat(c) => {
+                write!(f, "invalid character '{}' in number", c)
+            }
+            Self::NumberTooLong { offset } => {
+                write!(f, "number starting at offset {} is too long", offset)
+            }
+            Self::UnsupportedLiteral(lit) => write!(f, "'{}' is not a supported number", lit),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
+/// Byte range `start..end` of a lexeme in the source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A lexeme produced by [`Lexer::scan_recovering`]: either a valid token or
+/// a marker standing in for the bytes that failed to scan.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Lexeme {
+    Token(Token),
+    Invalid,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Recovered {
+    pub lexemes: Vec<(Span, Lexeme)>,
+    pub errors: Vec<(Span, Error)>,
+}
+
 pub trait Scan {
     fn scan(&mut self) -> Result<Token, Error>;
+
+    /// Where the last token, or the bytes that failed to scan, are in the source.
+    fn span(&self) -> Option<Span> {
+        None
+    }
+
+    /// Move past the bytes that made the last `scan` fail, so scanning can go on.
+    /// Returns `false` if that is not supported.
+    fn skip_invalid(&mut self) -> bool {
+        false
+    }
+}
+
+/// The decimal point of numbers. With `Comma` function arguments are
+/// separated by `;` instead, so `pow(1,5; 2)` is `pow(1.5, 2)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DecimalSeparator {
+    #[default]
+    Dot,
+    Comma,
 }
 
+/// Generous for any real number, small enough that pathological input fails fast.
+const DEFAULT_MAX_NUMBER_DIGITS: usize = 64;
+
 pub struct Lexer<'a> {
     src: &'a [u8],
     src_index: usize,
+    token_start: usize,
+    token_end: usize,
+    decimal_separator: DecimalSeparator,
+    max_number_digits: usize,
+    extern_names: &'a [&'a str],
+    comments: bool,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(src: &'a [u8]) -> Self {
-        Lexer { src, src_index: 0 }
+        Lexer {
+            src,
+            src_index: 0,
+            token_start: 0,
+            token_end: 0,
+            decimal_separator: DecimalSeparator::default(),
+            max_number_digits: DEFAULT_MAX_NUMBER_DIGITS,
+            extern_names: &[],
+            comments: false,
+        }
+    }
+
+    pub fn new_str(src: &'a str) -> Self {
+        Self::new(src.as_bytes())
+    }
+
+    pub fn with_decimal_separator(mut self, separator: DecimalSeparator) -> Self {
+        self.decimal_separator = separator;
+        self
+    }
+
+    pub fn decimal_separator(&self) -> DecimalSeparator {
+        self.decimal_separator
+    }
+
+    /// Longest number literal accepted, counting the dot and the exponent.
+    pub fn with_max_number_digits(mut self, max: usize) -> Self {
+        self.max_number_digits = max;
+        self
+    }
+
+    pub fn max_number_digits(&self) -> usize {
+        self.max_number_digits
+    }
+
+    /// Emit `Token::Comment` for a `#` and the rest of its line, for tools
+    /// that keep comments. The compiler skips them. Otherwise `#` is invalid.
+    pub fn with_comments(mut self, comments: bool) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    pub fn comments(&self) -> bool {
+        self.comments
+    }
+
+    /// Scan these names as `Token::Ident`, see `VirtualMachine::register_fn`.
+    /// A name takes precedence over a built-in function with the same prefix.
+    pub fn with_extern_names(mut self, names: &'a [&'a str]) -> Self {
+        self.extern_names = names;
+        self
+    }
+
+    fn decimal_point(&self) -> u8 {
+        match self.decimal_separator {
+            DecimalSeparator::Dot => b'.',
+            DecimalSeparator::Comma => b',',
+        }
+    }
+
+    fn argument_separator(&self) -> u8 {
+        match self.decimal_separator {
+            DecimalSeparator::Dot => b',',
+            DecimalSeparator::Comma => b';',
+        }
     }
 
     fn advance(&mut self) {
@@ -208,9 +697,50 @@ impl<'a> Lexer<'a> {
     }
 
     fn peek_word(&self, ch_len: usize) -> &[u8] {
-        &self.src[self.src_index..self.src_index + ch_len]
+        self.src
+            .get(self.src_index..self.src_index + ch_len)
+            .unwrap_or_default()
     }
 
+    /// Byte offset of the next character to scan.
+    pub fn position(&self) -> usize {
+        self.src_index
+    }
+
+    /// The part of the source not scanned yet.
+    pub fn remaining(&self) -> &[u8] {
+        self.src.get(self.src_index..).unwrap_or_default()
+    }
+
+    /// Scan the whole source without stopping at the first error.
+    /// The offending byte is skipped and an [`Lexeme::Invalid`] marker is
+    /// emitted in its place, so every problem can be reported at once.
+    pub fn scan_recovering(&mut self) -> Recovered {
+        let mut recovered = Recovered::default();
+        loop {
+            match self.scan() {
+                Ok(t) => recovered
+                    .lexemes
+                    .push((self.token_span(), Lexeme::Token(t))),
+                Err(Error::Eof) => return recovered,
+                Err(e) => {
+                    self.skip_invalid();
+                    recovered.lexemes.push((self.token_span(), Lexeme::Invalid));
+                    recovered.errors.push((self.token_span(), e));
+                }
+            }
+        }
+    }
+
+    fn token_span(&self) -> Span {
+        Span {
+            start: self.token_start,
+            end: self.token_end,
+        }
+    }
+
+    /// Skip spaces, tabs and newlines, returning the next byte or `Error::Eof`
+    /// if only whitespace is left.
     fn skip_whitespace(&mut self) -> Result<u8, Error> {
         while self.peek().ok_or(Error::Eof)?.is_ascii_whitespace() {
             self.src_index += 1;
@@ -228,32 +758,94 @@ impl<'a> Lexer<'a> {
         let mut dot = false;
         let mut exponent = false;
         let mut prev: Option<u8> = None;
+        let decimal_point = self.decimal_point();
 
         while let Some(c) = self.peek() {
-            if c == b'.' {
+            if c == decimal_point {
                 if dot || exponent {
                     return err(c);
                 }
                 dot = true;
             } else if c == b'-' {
                 if prev.is_some_and(|p| p != b'e') {
-                    return Ok(Token::Number(self.src[begin..self.src_index].into()));
+                    return self.number_token(begin);
                 }
             } else if c == b'e' {
-                if exponent || prev.is_some_and(|p| p != b'.' && !p.is_ascii_digit()) {
+                if exponent || prev.is_some_and(|p| p != decimal_point && !p.is_ascii_digit()) {
                     return err(c);
                 }
                 exponent = true;
             } else if !c.is_ascii_digit() {
-                if prev.is_some_and(|p| p == b'e' || p == b'.') {
+                // A trailing dot is accepted (`5.` is `5.0`), a dangling exponent is not
+                if prev.is_some_and(|p| p == b'e') {
                     return err(c);
                 }
-                return Ok(Token::Number(self.src[begin..self.src_index].into()));
+                return self.number_token(begin);
+            }
+            if self.src_index - begin == self.max_number_digits {
+                return Err(Error::NumberTooLong { offset: begin });
             }
             self.advance();
             prev = Some(c);
         }
-        Ok(Token::Number(self.src[begin..self.src_index].into()))
+        self.number_token(begin)
+    }
+
+    fn number_token(&mut self, begin: usize) -> Result<Token, Error> {
+        let suffix = self.peek_word(ANGLE_SUFFIX_LEN);
+        if ANGLE_SUFFIXES.contains(&suffix)
+            && self
+                .src
+                .get(self.src_index + ANGLE_SUFFIX_LEN)
+                .is_none_or(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+        {
+            self.src_index += ANGLE_SUFFIX_LEN;
+        }
+        let digits = &self.src[begin..self.src_index];
+        normalize_number(digits)?;
+        Ok(Token::Number(digits.into()))
+    }
+
+    /// A constant name must not run into a longer word, so `cos` is not `c` followed by `os`.
+    #[cfg(feature = "constants")]
+    fn parse_const(&mut self) -> Option<Token> {
+        let (name, constant) = CONSTANTS.iter().find(|(name, _)| {
+            self.peek_word(name.len()) == *name
+                && self
+                    .src
+                    .get(self.src_index + name.len())
+                    .is_none_or(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+        })?;
+        Some(self.consume_token(Token::Const(*constant), name.len()))
+    }
+
+    /// Results may be infinite or NaN, but literals may not: `1 / 0` is an error,
+    /// and so is writing `inf`.
+    fn reject_non_finite(&mut self) -> Result<(), Error> {
+        for literal in ["inf", "nan"] {
+            if self.peek_word(literal.len()) == literal.as_bytes()
+                && self
+                    .src
+                    .get(self.src_index + literal.len())
+                    .is_none_or(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+            {
+                self.src_index += literal.len();
+                return Err(Error::UnsupportedLiteral(literal));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_extern(&mut self) -> Option<Token> {
+        let name = self.extern_names.iter().find(|name| {
+            self.peek_word(name.len()) == name.as_bytes()
+                && self
+                    .src
+                    .get(self.src_index + name.len())
+                    .is_none_or(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+        })?;
+        let ident = Token::Ident(self.peek_word(name.len()).into());
+        Some(self.consume_token(ident, name.len()))
     }
 
     fn parse_fn(&mut self, first_ch: u8) -> Result<Token, Error> {
@@ -264,18 +856,46 @@ impl<'a> Lexer<'a> {
 
         match first_ch {
             b's' => {
+                if self.peek_word(4) == b"sinc" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Sinc), 4));
+                }
+                // before `sin`, or `sinh` would scan as `sin` and `h`
+                if self.peek_word(4) == b"sinh" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Sinh), 4));
+                }
                 if self.peek_word(3) == b"sin" {
                     return Ok(self.consume_token(Token::Func(FuncType::Sin), 3));
                 }
                 if self.peek_word(4) == b"sqrt" {
                     return Ok(self.consume_token(Token::Func(FuncType::Sqrt), 4));
                 }
+                if self.peek_word(3) == b"sec" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Sec), 3));
+                }
+                if self.peek_word(5) == b"ssqrt" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Ssqrt), 5));
+                }
+                if self.peek_word(4) == b"step" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Step), 4));
+                }
+                if self.peek_word(8) == b"saturate" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Saturate), 8));
+                }
                 err(first_ch)
             }
             b'c' => {
+                if self.peek_word(4) == b"cosh" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Cosh), 4));
+                }
                 if self.peek_word(3) == b"cos" {
                     return Ok(self.consume_token(Token::Func(FuncType::Cos), 3));
                 }
+                if self.peek_word(3) == b"csc" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Csc), 3));
+                }
+                if self.peek_word(3) == b"cot" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Cot), 3));
+                }
                 err(first_ch)
             }
             b'l' => {
@@ -288,6 +908,85 @@ impl<'a> Lexer<'a> {
                 if self.peek_word(3) == b"pow" {
                     return Ok(self.consume_token(Token::Func(FuncType::Pow), 3));
                 }
+                if self.peek_word(3) == b"phi" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Phi), 3));
+                }
+                if self.peek_word(9) == b"pctchange" {
+                    return Ok(self.consume_token(Token::Func(FuncType::PctChange), 9));
+                }
+                if self.peek_word(5) == b"pctof" {
+                    return Ok(self.consume_token(Token::Func(FuncType::PctOf), 5));
+                }
+                err(first_ch)
+            }
+            b'a' => {
+                if self.peek_word(3) == b"abs" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Abs), 3));
+                }
+                if self.peek_word(4) == b"asin" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Asin), 4));
+                }
+                if self.peek_word(4) == b"acos" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Acos), 4));
+                }
+                // the longer name first, or `atan2` would scan as `atan` and `2`
+                if self.peek_word(5) == b"atan2" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Atan2), 5));
+                }
+                if self.peek_word(4) == b"atan" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Atan), 4));
+                }
+                err(first_ch)
+            }
+            b'f' => {
+                if self.peek_word(3) == b"fma" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Fma), 3));
+                }
+                err(first_ch)
+            }
+            b'g' => {
+                if self.peek_word(3) == b"gcd" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Gcd), 3));
+                }
+                err(first_ch)
+            }
+            b'i' => {
+                if self.peek_word(4) == b"idiv" {
+                    return Ok(self.consume_token(Token::Func(FuncType::IDiv), 4));
+                }
+                err(first_ch)
+            }
+            b'm' => {
+                if self.peek_word(3) == b"min" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Min), 3));
+                }
+                if self.peek_word(3) == b"max" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Max), 3));
+                }
+                err(first_ch)
+            }
+            b'r' => {
+                if self.peek_word(8) == b"round_to" {
+                    return Ok(self.consume_token(Token::Func(FuncType::RoundTo), 8));
+                }
+                if self.peek_word(7) == b"roundto" {
+                    return Ok(self.consume_token(Token::Func(FuncType::RoundTo), 7));
+                }
+                if self.peek_word(4) == b"rand" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Rand), 4));
+                }
+                if self.peek_word(4) == b"rect" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Rect), 4));
+                }
+                err(first_ch)
+            }
+            b't' => {
+                if self.peek_word(3) == b"tau" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Tau), 3));
+                }
+                if self.peek_word(4) == b"tanh" {
+                    return Ok(self.consume_token(Token::Func(FuncType::Tanh), 4));
+                }
                 err(first_ch)
             }
             _ => err(first_ch),
@@ -297,24 +996,243 @@ impl<'a> Lexer<'a> {
 
 impl<'a> Scan for Lexer<'a> {
     fn scan(&mut self) -> Result<Token, Error> {
+        let _ = self.skip_whitespace();
+        self.token_start = self.src_index;
+        let res = self.scan_token();
+        // an invalid token spans up to and including the offending byte
+        self.token_end = match res {
+            Err(Error::InvalidChar(_) | Error::InvalidNumberFormat(_)) => {
+                (self.src_index + 1).min(self.src.len())
+            }
+            _ => self.src_index,
+        };
+        res
+    }
+
+    fn span(&self) -> Option<Span> {
+        Some(self.token_span())
+    }
+
+    fn skip_invalid(&mut self) -> bool {
+        self.src_index = self.token_end;
+        true
+    }
+}
+
+impl<'a> Lexer<'a> {
+    fn scan_token(&mut self) -> Result<Token, Error> {
         let c = self.skip_whitespace()?;
         if c.is_ascii_digit() {
             return self.consume_number();
         }
+        #[cfg(feature = "constants")]
+        if let Some(t) = self.parse_const() {
+            return Ok(t);
+        }
         match c {
             b'(' => Ok(self.consume_token(Token::LeftParen, 1)),
             b')' => Ok(self.consume_token(Token::RightParen, 1)),
+            b'[' => Ok(self.consume_token(Token::LeftBracket, 1)),
+            b']' => Ok(self.consume_token(Token::RightBracket, 1)),
             b'+' => Ok(self.consume_token(Token::Plus, 1)),
             b'-' => Ok(self.consume_token(Token::Minus, 1)),
             b'*' => Ok(self.consume_token(Token::Mult, 1)),
             b'/' => Ok(self.consume_token(Token::Div, 1)),
-            b',' => Ok(self.consume_token(Token::Comma, 1)),
+            c if c == self.argument_separator() => Ok(self.consume_token(Token::Comma, 1)),
+            b';' => Ok(self.consume_token(Token::Semicolon, 1)),
+            b'|' => Ok(self.consume_token(Token::Bar, 1)),
+            b'&' => Ok(self.consume_token(Token::Ampersand, 1)),
+            b'^' => Ok(self.consume_token(Token::Caret, 1)),
+            b'%' => Ok(self.consume_token(Token::Percent, 1)),
+            b'<' if self.peek_word(2) == b"<<" => Ok(self.consume_token(Token::ShiftLeft, 2)),
+            b'>' if self.peek_word(2) == b">>" => Ok(self.consume_token(Token::ShiftRight, 2)),
             b'a' if self.peek_word(3) == b"ans" => Ok(self.consume_token(Token::Ans, 3)),
-            ch => self.parse_fn(ch),
+            b'#' if self.comments => {
+                let rest = &self.src[self.src_index..];
+                let len = rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len());
+                let comment = Token::Comment(rest[..len].into());
+                Ok(self.consume_token(comment, len))
+            }
+            ch => match self.parse_extern() {
+                Some(ident) => Ok(ident),
+                None => self.reject_non_finite().and_then(|_| self.parse_fn(ch)),
+            },
         }
     }
 }
 
+/// Wraps a scanner and inserts a `Token::Mult` between adjacent operands, so
+/// that `2(3)` scans as `2 * (3)` and `2sqrt(4)` as `2 * sqrt(4)`.
+pub struct ImplicitMul<S: Scan> {
+    inner: S,
+    prev: Option<Token>,
+    pending: Option<Token>,
+}
+
+impl<S: Scan> ImplicitMul<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            prev: None,
+            pending: None,
+        }
+    }
+
+    /// `ans` is not an operand end on the left, since `ans(2)` is not `ans * 2`.
+    fn is_implicit_mul(prev: Token, next: Token) -> bool {
+        matches!(
+            (prev, next),
+            (
+                Token::Number(_) | Token::RightParen | Token::RightBracket | Token::Const(_),
+                Token::LeftParen
+                    | Token::LeftBracket
+                    | Token::Func(_)
+                    | Token::Ident(_)
+                    | Token::Const(_)
+                    | Token::Ans
+            ) | (Token::RightParen | Token::RightBracket, Token::Number(_))
+        )
+    }
+}
+
+impl<S: Scan> Scan for ImplicitMul<S> {
+    fn scan(&mut self) -> Result<Token, Error> {
+        let res = match self.pending.take() {
+            Some(token) => Ok(token),
+            None => match (self.prev, self.inner.scan()) {
+                (Some(prev), Ok(next)) if Self::is_implicit_mul(prev, next) => {
+                    self.pending = Some(next);
+                    Ok(Token::Mult)
+                }
+                (_, res) => res,
+            },
+        };
+        self.prev = res.ok();
+        res
+    }
+
+    /// An injected `Mult` has an empty span at the start of the token after it.
+    fn span(&self) -> Option<Span> {
+        let span = self.inner.span()?;
+        match self.pending {
+            Some(_) => Some(Span {
+                start: span.start,
+                end: span.start,
+            }),
+            None => Some(span),
+        }
+    }
+
+    fn skip_invalid(&mut self) -> bool {
+        self.inner.skip_invalid()
+    }
+}
+
+const ANGLE_SUFFIX_LEN: usize = 3;
+const ANGLE_SUFFIXES: [&[u8]; 2] = [b"deg", b"rad"];
+
+/// The unit given by a `deg` or `rad` suffix on a number.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AngleSuffix {
+    Degrees,
+    Radians,
+}
+
+/// Split the `deg` or `rad` suffix off the bytes of a `Token::Number`.
+pub fn split_angle_suffix(bytes: &[u8]) -> (&[u8], Option<AngleSuffix>) {
+    if let Some(bytes) = bytes.strip_suffix(b"deg") {
+        (bytes, Some(AngleSuffix::Degrees))
+    } else if let Some(bytes) = bytes.strip_suffix(b"rad") {
+        (bytes, Some(AngleSuffix::Radians))
+    } else {
+        (bytes, None)
+    }
+}
+
+/// The value of the bytes of a `Token::Number`. A `,` is read as the decimal
+/// point, as scanned with `DecimalSeparator::Comma`. Words like `inf` or `nan`
+/// are not numbers. A `deg` or `rad` suffix is ignored: the value is converted
+/// at run time, as it depends on the angle mode of the virtual machine.
+pub fn normalize_number(bytes: &[u8]) -> Result<f64, Error> {
+    let (bytes, _) = split_angle_suffix(bytes);
+    let invalid = || Error::InvalidNumberFormat(bytes.last().map_or(' ', |&c| c as char));
+    if !bytes
+        .iter()
+        .all(|c| c.is_ascii_digit() || b".,eE+-".contains(c))
+    {
+        return Err(invalid());
+    }
+    // only ASCII is left
+    let chars = core::str::from_utf8(bytes).map_err(|_| invalid())?;
+    let parsed = if chars.contains(',') {
+        chars.replace(',', ".").parse::<f64>()
+    } else {
+        chars.parse::<f64>()
+    };
+    parsed.map_err(|_| invalid())
+}
+
+/// Scan the whole source, stopping at the first error.
+pub fn tokenize(src: &[u8]) -> Result<Vec<Token>, Error> {
+    let mut lexer = Lexer::new(src);
+    let mut tokens = Vec::new();
+    loop {
+        match lexer.scan() {
+            Ok(t) => tokens.push(t),
+            Err(Error::Eof) => return Ok(tokens),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Re-emit `src` with canonical spacing: binary operators are surrounded by
+/// spaces, commas are followed by one, and nothing else is spaced.
+pub fn format_expr(src: &str) -> Result<String, Error> {
+    let mut formatted = String::with_capacity(src.len());
+    // whether the next token starts an operand, as in the compiler
+    let mut prefix = true;
+    let mut abs_depth = 0usize;
+    for token in tokenize(src.as_bytes())? {
+        let text = String::from(token);
+        match token {
+            Token::Number(_)
+            | Token::Const(_)
+            | Token::Ans
+            | Token::RightParen
+            | Token::RightBracket
+            | Token::Percent => {
+                formatted.push_str(&text);
+                prefix = false;
+            }
+            Token::Func(_) | Token::Ident(_) | Token::LeftParen | Token::LeftBracket => {
+                formatted.push_str(&text);
+                prefix = true;
+            }
+            Token::Comma => {
+                formatted.push_str(", ");
+                prefix = true;
+            }
+            Token::Minus if prefix => formatted.push('-'),
+            Token::Bar if prefix => {
+                formatted.push('|');
+                abs_depth += 1;
+            }
+            Token::Bar if abs_depth > 0 => {
+                formatted.push('|');
+                abs_depth -= 1;
+                prefix = false;
+            }
+            _ => {
+                formatted.push(' ');
+                formatted.push_str(&text);
+                formatted.push(' ');
+                prefix = true;
+            }
+        }
+    }
+    Ok(formatted)
+}
+
 #[cfg(test)]
 mod lexer_tests {
     use super::*;
@@ -538,4 +1456,630 @@ mod lexer_tests {
         assert_eq!(t.unwrap(), Token::Func(FuncType::Pow));
         assert_eq!(l.scan(), Err(Error::Eof))
     }
+
+    #[test]
+    fn test_round_to() {
+        let mut l = Lexer::new(b"round_to".as_slice());
+        let t = l.scan();
+        assert!(t.is_ok());
+        assert_eq!(t.unwrap(), Token::Func(FuncType::RoundTo));
+        assert_eq!(l.scan(), Err(Error::Eof));
+
+        let mut l = Lexer::new(b"roundto(".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::RoundTo)));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+    }
+
+    #[test]
+    fn test_hyperbolic_not_split() {
+        let mut l = Lexer::new(b"sinh(1)+cosh(1)*tanh(1)".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sinh)));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        for _ in 0..3 {
+            l.scan().unwrap();
+        }
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Cosh)));
+        for _ in 0..4 {
+            l.scan().unwrap();
+        }
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Tanh)));
+
+        let mut l = Lexer::new(b"sin(1)".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sin)));
+    }
+
+    #[test]
+    fn test_truncated_function_name() {
+        let mut l = Lexer::new(b"s".as_slice());
+        assert_eq!(l.scan(), Err(Error::InvalidChar('s')));
+    }
+
+    #[test]
+    fn test_trailing_dot() {
+        let mut l = Lexer::new(b"5.".as_slice());
+        let token = l.scan();
+        assert!(token.is_ok());
+        assert_eq!(token.unwrap(), Token::Number(b"5.".as_slice().into()));
+        assert_eq!(l.scan(), Err(Error::Eof));
+    }
+
+    #[test]
+    fn test_trailing_dot_followed_by_operator() {
+        let mut l = Lexer::new(b"5.+1".as_slice());
+        let token = l.scan();
+        assert!(token.is_ok());
+        assert_eq!(token.unwrap(), Token::Number(b"5.".as_slice().into()));
+
+        let token = l.scan();
+        assert!(token.is_ok());
+        assert_eq!(token.unwrap(), Token::Plus);
+
+        let token = l.scan();
+        assert!(token.is_ok());
+        assert_eq!(token.unwrap(), Token::Number(b"1".as_slice().into()));
+        assert_eq!(l.scan(), Err(Error::Eof));
+    }
+
+    #[test]
+    fn test_trailing_dot_with_exponent() {
+        let mut l = Lexer::new(b"5.e2".as_slice());
+        let token = l.scan();
+        assert!(token.is_ok());
+        assert_eq!(token.unwrap(), Token::Number(b"5.e2".as_slice().into()));
+        assert_eq!(l.scan(), Err(Error::Eof));
+    }
+
+    #[test]
+    fn test_dangling_exponent() {
+        let mut l = Lexer::new(b"5e+1".as_slice());
+        let token = l.scan();
+        assert!(token.is_err());
+        assert_eq!(token.unwrap_err(), Error::InvalidNumberFormat('+'));
+    }
+
+    #[test]
+    fn test_abs() {
+        let mut l = Lexer::new(b"abs".as_slice());
+        let t = l.scan();
+        assert!(t.is_ok());
+        assert_eq!(t.unwrap(), Token::Func(FuncType::Abs));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_bar() {
+        let mut l = Lexer::new(b"|".as_slice());
+        let t = l.scan();
+        assert!(t.is_ok());
+        assert_eq!(t.unwrap(), Token::Bar);
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_bitwise_operators() {
+        let mut l = Lexer::new(b"& << >> |".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Ampersand));
+        assert_eq!(l.scan(), Ok(Token::ShiftLeft));
+        assert_eq!(l.scan(), Ok(Token::ShiftRight));
+        assert_eq!(l.scan(), Ok(Token::Bar));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_incomplete_shift() {
+        let mut l = Lexer::new(b"1 < 2".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Number(b"1".as_slice().into())));
+        assert_eq!(l.scan(), Err(Error::InvalidChar('<')));
+    }
+
+    #[test]
+    fn test_caret() {
+        let mut l = Lexer::new(b"2^3".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Caret));
+        assert_eq!(l.scan(), Ok(Token::Number(b"3".as_slice().into())));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_scan_recovering() {
+        let mut l = Lexer::new(b"1 @ 2 # 3".as_slice());
+        let recovered = l.scan_recovering();
+        let span = |start, end| Span { start, end };
+        assert_eq!(
+            recovered.errors,
+            vec![
+                (span(2, 3), Error::InvalidChar('@')),
+                (span(6, 7), Error::InvalidChar('#')),
+            ]
+        );
+        assert_eq!(
+            recovered.lexemes,
+            vec![
+                (
+                    span(0, 1),
+                    Lexeme::Token(Token::Number(b"1".as_slice().into()))
+                ),
+                (span(2, 3), Lexeme::Invalid),
+                (
+                    span(4, 5),
+                    Lexeme::Token(Token::Number(b"2".as_slice().into()))
+                ),
+                (span(6, 7), Lexeme::Invalid),
+                (
+                    span(8, 9),
+                    Lexeme::Token(Token::Number(b"3".as_slice().into()))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_recovering_invalid_number() {
+        let mut l = Lexer::new(b"1.2.3 + 1".as_slice());
+        let recovered = l.scan_recovering();
+        assert_eq!(
+            recovered.errors,
+            vec![(Span { start: 0, end: 4 }, Error::InvalidNumberFormat('.'))]
+        );
+        assert_eq!(recovered.lexemes.len(), 4);
+        assert_eq!(
+            recovered.lexemes.last(),
+            Some(&(
+                Span { start: 8, end: 9 },
+                Lexeme::Token(Token::Number(b"1".as_slice().into()))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_percent() {
+        let mut l = Lexer::new(b"50%".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Number(b"50".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Percent));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[cfg(feature = "constants")]
+    #[test]
+    fn test_constants() {
+        let mut l = Lexer::new(b"c*g+h cos".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Const(Constant::SpeedOfLight)));
+        assert_eq!(l.scan(), Ok(Token::Mult));
+        assert_eq!(l.scan(), Ok(Token::Const(Constant::Gravity)));
+        assert_eq!(l.scan(), Ok(Token::Plus));
+        assert_eq!(l.scan(), Ok(Token::Const(Constant::Planck)));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Cos)));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[cfg(not(feature = "constants"))]
+    #[test]
+    fn test_constants_disabled() {
+        let mut l = Lexer::new(b"c".as_slice());
+        assert_eq!(l.scan(), Err(Error::InvalidChar('c')));
+    }
+
+    #[test]
+    fn test_min_max() {
+        let mut l = Lexer::new(b"min max".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Min)));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Max)));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_reciprocal_trig() {
+        let mut l = Lexer::new(b"sec csc cot cos sin".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sec)));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Csc)));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Cot)));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Cos)));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sin)));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_position() {
+        let mut l = Lexer::new(b"12 + 3".as_slice());
+        assert_eq!(l.position(), 0);
+        assert_eq!(l.scan(), Ok(Token::Number(b"12".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Plus));
+        assert_eq!(l.position(), 4);
+        assert_eq!(l.remaining(), b" 3");
+        assert_eq!(l.scan(), Ok(Token::Number(b"3".as_slice().into())));
+        assert_eq!(l.scan(), Err(Error::Eof));
+        assert_eq!(l.position(), 6);
+        assert!(l.remaining().is_empty());
+    }
+
+    #[test]
+    fn test_span() {
+        let mut l = Lexer::new(b" sin @".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sin)));
+        assert_eq!(l.span(), Some(Span { start: 1, end: 4 }));
+        assert_eq!(l.scan(), Err(Error::InvalidChar('@')));
+        assert_eq!(l.span(), Some(Span { start: 5, end: 6 }));
+        assert!(l.skip_invalid());
+        assert_eq!(l.scan(), Err(Error::Eof));
+    }
+
+    #[test]
+    fn test_idiv() {
+        let mut l = Lexer::new(b"idiv".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::IDiv)));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_whitespace_before_function_paren() {
+        let mut l = Lexer::new(b"sqrt ( 4 ) sin\n(2)".as_slice());
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sqrt)));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        assert_eq!(l.scan(), Ok(Token::Number(b"4".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::RightParen));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sin)));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::RightParen));
+        assert_eq!(l.scan(), Err(Error::Eof))
+    }
+
+    #[test]
+    fn test_all_functions() {
+        let all: Vec<_> = FuncType::all().collect();
+        let from_codes: Vec<_> = (0..=u8::MAX)
+            .filter_map(|code| FuncType::try_from(code).ok())
+            .collect();
+        assert_eq!(all, from_codes);
+        for (i, f) in all.iter().enumerate() {
+            assert!(!all[i + 1..].contains(f));
+        }
+    }
+
+    #[test]
+    fn test_function_names_scan() {
+        for f in FuncType::all() {
+            let mut l = Lexer::new(f.name().as_bytes());
+            assert_eq!(l.scan(), Ok(Token::Func(f)));
+            assert_eq!(l.scan(), Err(Error::Eof));
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            Error::Eof,
+            Error::InvalidChar('x'),
+            Error::InvalidNumberFormat('.'),
+            Error::NumberTooLong { offset: 0 },
+            Error::UnsupportedLiteral("inf"),
+        ];
+        let codes: std::collections::HashSet<_> = errors.iter().map(Error::code).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert_eq!(Error::InvalidChar('x').code(), "E_INVALID_CHAR");
+    }
+
+    struct TokenStream(std::vec::IntoIter<Token>);
+
+    impl Scan for TokenStream {
+        fn scan(&mut self) -> Result<Token, Error> {
+            self.0.next().ok_or(Error::Eof)
+        }
+    }
+
+    fn implicit_mul(tokens: Vec<Token>) -> Vec<Token> {
+        let mut scanner = ImplicitMul::new(TokenStream(tokens.into_iter()));
+        core::iter::from_fn(|| scanner.scan().ok()).collect()
+    }
+
+    #[test]
+    fn test_implicit_mul() {
+        let two = Token::Number(b"2".as_slice().into());
+        let three = Token::Number(b"3".as_slice().into());
+        assert_eq!(
+            implicit_mul(vec![two, Token::LeftParen, three, Token::RightParen]),
+            vec![two, Token::Mult, Token::LeftParen, three, Token::RightParen]
+        );
+        assert_eq!(
+            implicit_mul(vec![Token::RightParen, Token::LeftParen]),
+            vec![Token::RightParen, Token::Mult, Token::LeftParen]
+        );
+        assert_eq!(
+            implicit_mul(vec![two, Token::Func(FuncType::Sqrt)]),
+            vec![two, Token::Mult, Token::Func(FuncType::Sqrt)]
+        );
+        assert_eq!(
+            implicit_mul(vec![Token::Ans, Token::LeftParen, two]),
+            vec![Token::Ans, Token::LeftParen, two]
+        );
+        assert_eq!(
+            implicit_mul(vec![two, Token::Plus, three]),
+            vec![two, Token::Plus, three]
+        );
+    }
+
+    #[test]
+    fn test_implicit_mul_span() {
+        let mut l = ImplicitMul::new(Lexer::new(b"2 (3)"));
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Mult));
+        assert_eq!(l.span(), Some(Span { start: 2, end: 2 }));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        assert_eq!(l.span(), Some(Span { start: 2, end: 3 }));
+    }
+
+    #[test]
+    fn test_func_domain() {
+        assert_eq!(FuncType::Sqrt.domain().0, 0.0);
+        assert_eq!(FuncType::Sin.domain(), (f64::NEG_INFINITY, f64::INFINITY));
+        assert_eq!(FuncType::Sqrt.description(), "sqrt(x): x ≥ 0");
+        assert!(FuncType::all().all(|f| f.description().starts_with(f.name())));
+    }
+
+    #[test]
+    fn test_priority_next_prev() {
+        let all = [
+            Priority::Null,
+            Priority::Comma,
+            Priority::Number,
+            Priority::Bitwise,
+            Priority::Term,
+            Priority::Factor,
+            Priority::Unary,
+            Priority::Power,
+            Priority::Postfix,
+            Priority::Group,
+        ];
+        for p in &all[..all.len() - 1] {
+            assert_eq!(p.next().prev(), *p);
+            assert!(p.next() > *p);
+        }
+        for p in &all[1..] {
+            assert_eq!(p.prev().next(), *p);
+        }
+        assert_eq!(Priority::Group.next(), Priority::Group);
+        assert_eq!(Priority::Null.prev(), Priority::Null);
+    }
+
+    #[test]
+    fn test_priority_of() {
+        assert_eq!(priority_of(&Token::Plus), Priority::Term);
+        assert!(priority_of(&Token::Mult) > priority_of(&Token::Minus));
+        assert!(priority_of(&Token::Caret) > priority_of(&Token::Minus));
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize(b"1 + ans"),
+            Ok(vec![
+                Token::Number(b"1".as_slice().into()),
+                Token::Plus,
+                Token::Ans
+            ])
+        );
+        assert_eq!(tokenize(b"1 @"), Err(Error::InvalidChar('@')));
+    }
+
+    #[test]
+    fn test_format_expr() {
+        assert_eq!(format_expr("1+2*sin(3 )"), Ok("1 + 2 * sin(3)".into()));
+        assert_eq!(format_expr("-( 1+2 )*-3"), Ok("-(1 + 2) * -3".into()));
+        assert_eq!(format_expr("max(1,2 ,3)"), Ok("max(1, 2, 3)".into()));
+        assert_eq!(format_expr("|-1|+2|3"), Ok("|-1| + 2 | 3".into()));
+        assert_eq!(format_expr("2^3<<1 + 50%"), Ok("2 ^ 3 << 1 + 50%".into()));
+        assert_eq!(format_expr("ans (2)"), Ok("ans(2)".into()));
+        assert_eq!(format_expr("1 + #"), Err(Error::InvalidChar('#')));
+    }
+
+    #[test]
+    fn test_decimal_comma() {
+        let mut l = Lexer::new(b"1,5").with_decimal_separator(DecimalSeparator::Comma);
+        assert_eq!(l.decimal_separator(), DecimalSeparator::Comma);
+        assert_eq!(l.scan(), Ok(Token::Number(b"1,5".as_slice().into())));
+        assert_eq!(l.scan(), Err(Error::Eof));
+
+        let mut l = Lexer::new(b"pow(2; 3)").with_decimal_separator(DecimalSeparator::Comma);
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Pow)));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Comma));
+        assert_eq!(l.scan(), Ok(Token::Number(b"3".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::RightParen));
+
+        let mut l = Lexer::new(b"1.5");
+        assert_eq!(l.scan(), Ok(Token::Number(b"1.5".as_slice().into())));
+        let mut l = Lexer::new(b"1;5");
+        assert_eq!(l.scan(), Ok(Token::Number(b"1".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Semicolon));
+        assert_eq!(l.scan(), Ok(Token::Number(b"5".as_slice().into())));
+    }
+
+    #[test]
+    fn test_brackets() {
+        let mut l = Lexer::new(b"[1]");
+        assert_eq!(l.scan(), Ok(Token::LeftBracket));
+        assert_eq!(l.scan(), Ok(Token::Number(b"1".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::RightBracket));
+        assert_eq!(format_expr("[1+2 ]*3"), Ok("[1 + 2] * 3".into()));
+    }
+
+    #[test]
+    fn test_tabs_and_newlines() {
+        assert_eq!(
+            tokenize(b"1\t+\n2"),
+            Ok(vec![
+                Token::Number(b"1".as_slice().into()),
+                Token::Plus,
+                Token::Number(b"2".as_slice().into()),
+            ])
+        );
+        assert_eq!(
+            tokenize(b"\r\n sqrt\t(4)\n"),
+            Ok(vec![
+                Token::Func(FuncType::Sqrt),
+                Token::LeftParen,
+                Token::Number(b"4".as_slice().into()),
+                Token::RightParen,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_trailing_whitespace() {
+        let mut l = Lexer::new(b"1 + 2   ");
+        assert_eq!(l.scan(), Ok(Token::Number(b"1".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Plus));
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        assert_eq!(l.scan(), Err(Error::Eof));
+        assert_eq!(l.scan(), Err(Error::Eof));
+    }
+
+    #[test]
+    fn test_whitespace_only() {
+        for src in [b"".as_slice(), b"   ", b" \t\n\r\n"] {
+            let mut l = Lexer::new(src);
+            assert_eq!(l.scan(), Err(Error::Eof));
+            assert_eq!(l.scan(), Err(Error::Eof));
+            assert!(l.remaining().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_normalize_number() {
+        assert_eq!(normalize_number(b"42"), Ok(42.0));
+        assert_eq!(normalize_number(b"12.5"), Ok(12.5));
+        assert_eq!(normalize_number(b"5."), Ok(5.0));
+        assert_eq!(normalize_number(b"1e3"), Ok(1000.0));
+        assert_eq!(normalize_number(b"2.5e-2"), Ok(0.025));
+        assert_eq!(normalize_number(b"1,5"), Ok(1.5));
+        assert_eq!(
+            normalize_number(b"1e-"),
+            Err(Error::InvalidNumberFormat('-'))
+        );
+        assert_eq!(
+            normalize_number(b"1..2"),
+            Err(Error::InvalidNumberFormat('2'))
+        );
+        assert_eq!(
+            normalize_number(b"nan"),
+            Err(Error::InvalidNumberFormat('n'))
+        );
+        assert!(normalize_number(b"").is_err());
+    }
+
+    #[test]
+    fn test_dangling_exponent_sign() {
+        let mut l = Lexer::new(b"1e-");
+        assert_eq!(l.scan(), Err(Error::InvalidNumberFormat('-')));
+    }
+
+    #[test]
+    fn test_number_too_long() {
+        let src = "1 + ".to_string() + &"9".repeat(DEFAULT_MAX_NUMBER_DIGITS + 1);
+        let mut l = Lexer::new(src.as_bytes());
+        assert!(l.scan().is_ok());
+        assert!(l.scan().is_ok());
+        assert_eq!(l.scan(), Err(Error::NumberTooLong { offset: 4 }));
+
+        let mut l = Lexer::new(b"1.25").with_max_number_digits(4);
+        assert_eq!(l.scan(), Ok(Token::Number(b"1.25".as_slice().into())));
+        let mut l = Lexer::new(b"1.255").with_max_number_digits(4);
+        assert_eq!(l.scan(), Err(Error::NumberTooLong { offset: 0 }));
+    }
+
+    #[test]
+    fn test_angle_suffix() {
+        let mut l = Lexer::new(b"90deg+1.5rad");
+        assert_eq!(l.scan(), Ok(Token::Number(b"90deg".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Plus));
+        assert_eq!(l.scan(), Ok(Token::Number(b"1.5rad".as_slice().into())));
+        assert_eq!(l.scan(), Err(Error::Eof));
+
+        let mut l = Lexer::new(b"2rand");
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Rand)));
+
+        assert_eq!(normalize_number(b"180deg"), Ok(180.0));
+        assert_eq!(normalize_number(b"2rad"), Ok(2.0));
+        assert_eq!(
+            split_angle_suffix(b"180deg"),
+            (b"180".as_slice(), Some(AngleSuffix::Degrees))
+        );
+        assert_eq!(
+            split_angle_suffix(b"2rad"),
+            (b"2".as_slice(), Some(AngleSuffix::Radians))
+        );
+        assert_eq!(split_angle_suffix(b"2"), (b"2".as_slice(), None));
+        assert!(normalize_number(b"deg").is_err());
+        assert!(normalize_number(b"1degdeg").is_err());
+    }
+
+    #[test]
+    fn test_extern_names() {
+        let names = ["double", "sinc"];
+        let mut l = Lexer::new(b"double(2) + sinc(0) + sin(doubles)").with_extern_names(&names);
+        assert_eq!(l.scan(), Ok(Token::Ident(b"double".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        l.scan().unwrap();
+        l.scan().unwrap();
+        l.scan().unwrap();
+        assert_eq!(l.scan(), Ok(Token::Ident(b"sinc".as_slice().into())));
+        for _ in 0..4 {
+            l.scan().unwrap();
+        }
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sin)));
+        assert_eq!(l.scan(), Ok(Token::LeftParen));
+        assert_eq!(l.scan(), Err(Error::InvalidChar('d')));
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut l = Lexer::new(b"1 + 2 # note\n* 3").with_comments(true);
+        assert_eq!(l.scan(), Ok(Token::Number(b"1".as_slice().into())));
+        assert_eq!(l.scan(), Ok(Token::Plus));
+        assert_eq!(l.scan(), Ok(Token::Number(b"2".as_slice().into())));
+        let comment = l.scan().unwrap();
+        assert_eq!(comment, Token::Comment(b"# note".as_slice().into()));
+        assert_eq!(String::from(comment), "# note");
+        assert_eq!(l.scan(), Ok(Token::Mult));
+
+        let mut l = Lexer::new(b"# note");
+        assert_eq!(l.scan(), Err(Error::InvalidChar('#')));
+    }
+
+    #[test]
+    fn test_non_finite_literals() {
+        let mut l = Lexer::new(b"1 + inf");
+        l.scan().unwrap();
+        l.scan().unwrap();
+        assert_eq!(l.scan(), Err(Error::UnsupportedLiteral("inf")));
+        assert_eq!(l.token_span(), Span { start: 4, end: 7 });
+        assert_eq!(
+            Lexer::new(b"nan").scan(),
+            Err(Error::UnsupportedLiteral("nan"))
+        );
+        assert_eq!(Lexer::new(b"info").scan(), Err(Error::InvalidChar('i')));
+    }
+
+    #[test]
+    fn test_new_str() {
+        let mut l = Lexer::new_str("sqrt(2)");
+        assert_eq!(l.scan(), Ok(Token::Func(FuncType::Sqrt)));
+        assert_eq!(l.remaining(), b"(2)");
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(Error::InvalidChar('@').to_string(), "invalid character '@'");
+        assert_eq!(
+            Error::NumberTooLong { offset: 4 }.to_string(),
+            "number starting at offset 4 is too long"
+        );
+        assert_eq!(
+            Error::UnsupportedLiteral("inf").to_string(),
+            "'inf' is not a supported number"
+        );
+    }
 }
@@ -1,5 +1,85 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("either the `std` or the `libm` feature must be enabled");
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod app;
+pub mod ast;
 pub mod compiler;
 pub mod lexer;
+mod math;
 mod misc;
+#[cfg(feature = "rational")]
+pub mod rational;
 pub mod vm;
+
+#[cfg(feature = "std")]
+pub use app::{eval_reader, ApplicationError};
+
+use compiler::{Compile, Compiler};
+use lexer::Lexer;
+use vm::VirtualMachine;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    Compiler(compiler::Error),
+    VirtualMachine(vm::Error),
+}
+
+impl core::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Compiler(e) => write!(f, "{}", e),
+            Self::VirtualMachine(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EvalError {}
+
+impl From<compiler::Error> for EvalError {
+    fn from(value: compiler::Error) -> Self {
+        Self::Compiler(value)
+    }
+}
+
+impl From<vm::Error> for EvalError {
+    fn from(value: vm::Error) -> Self {
+        Self::VirtualMachine(value)
+    }
+}
+
+/// Lex, compile and interpret `src` with default settings, without printing
+/// anything. Every call starts afresh, so `ans` is not available.
+pub fn interpret_source(src: &[u8]) -> Result<f64, EvalError> {
+    let mut compiler = Compiler::default();
+    compiler.compile(&mut Lexer::new(src))?;
+    Ok(VirtualMachine::default().interpret(compiler.opcodes())?)
+}
+
+#[cfg(test)]
+mod lib_tests {
+    use super::*;
+
+    #[test]
+    fn test_interpret_source() {
+        assert_eq!(interpret_source(b"1 + 2 * 3"), Ok(7.0));
+        assert_eq!(interpret_source(b"2; ans * 4"), Ok(8.0));
+        assert_eq!(
+            interpret_source(b"(1"),
+            Err(EvalError::Compiler(compiler::Error::UnterminedGroup))
+        );
+        assert_eq!(
+            interpret_source(b"1 / 0"),
+            Err(EvalError::VirtualMachine(vm::Error::DivisionByZero))
+        );
+        assert_eq!(
+            interpret_source(b"ans").unwrap_err().to_string(),
+            "no previous result for 'ans'"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Floating point functions that are not available in `core`.
+//! They forward to the `f64` inherent methods with `std` and to `libm` without it.
+
+#[cfg(feature = "std")]
+mod imp {
+    pub fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    pub fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+
+    pub fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+
+    pub fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+
+    pub fn powf(x: f64, y: f64) -> f64 {
+        x.powf(y)
+    }
+
+    pub fn powi(x: f64, n: i32) -> f64 {
+        x.powi(n)
+    }
+
+    pub fn round(x: f64) -> f64 {
+        x.round()
+    }
+
+    pub fn fract(x: f64) -> f64 {
+        x.fract()
+    }
+
+    pub fn trunc(x: f64) -> f64 {
+        x.trunc()
+    }
+
+    pub fn mul_add(x: f64, y: f64, z: f64) -> f64 {
+        x.mul_add(y, z)
+    }
+
+    pub fn asin(x: f64) -> f64 {
+        x.asin()
+    }
+
+    pub fn acos(x: f64) -> f64 {
+        x.acos()
+    }
+
+    pub fn atan(x: f64) -> f64 {
+        x.atan()
+    }
+
+    pub fn atan2(y: f64, x: f64) -> f64 {
+        y.atan2(x)
+    }
+
+    pub fn sinh(x: f64) -> f64 {
+        x.sinh()
+    }
+
+    pub fn cosh(x: f64) -> f64 {
+        x.cosh()
+    }
+
+    pub fn tanh(x: f64) -> f64 {
+        x.tanh()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    pub fn sin(x: f64) -> f64 {
+        libm::sin(x)
+    }
+
+    pub fn cos(x: f64) -> f64 {
+        libm::cos(x)
+    }
+
+    pub fn ln(x: f64) -> f64 {
+        libm::log(x)
+    }
+
+    pub fn sqrt(x: f64) -> f64 {
+        libm::sqrt(x)
+    }
+
+    pub fn powf(x: f64, y: f64) -> f64 {
+        libm::pow(x, y)
+    }
+
+    pub fn powi(x: f64, n: i32) -> f64 {
+        libm::pow(x, n as f64)
+    }
+
+    pub fn round(x: f64) -> f64 {
+        libm::round(x)
+    }
+
+    pub fn fract(x: f64) -> f64 {
+        x - libm::trunc(x)
+    }
+
+    pub fn trunc(x: f64) -> f64 {
+        libm::trunc(x)
+    }
+
+    pub fn mul_add(x: f64, y: f64, z: f64) -> f64 {
+        libm::fma(x, y, z)
+    }
+
+    pub fn asin(x: f64) -> f64 {
+        libm::asin(x)
+    }
+
+    pub fn acos(x: f64) -> f64 {
+        libm::acos(x)
+    }
+
+    pub fn atan(x: f64) -> f64 {
+        libm::atan(x)
+    }
+
+    pub fn atan2(y: f64, x: f64) -> f64 {
+        libm::atan2(y, x)
+    }
+
+    pub fn sinh(x: f64) -> f64 {
+        libm::sinh(x)
+    }
+
+    pub fn cosh(x: f64) -> f64 {
+        libm::cosh(x)
+    }
+
+    pub fn tanh(x: f64) -> f64 {
+        libm::tanh(x)
+    }
+}
+
+pub use imp::*;
@@ -0,0 +1,454 @@
+//! Exact evaluation of `+`, `-`, `*` and `/` on fractions.
+//! Everything else, like `sin` or `sqrt`, is computed by the regular virtual machine
+//! and turns the result into a float.
+
+use alloc::vec::Vec;
+use core::fmt::Display;
+
+use crate::{
+    compiler::{self, read_f64_operand, write_f64_operand, Compile, Compiler, Op},
+    lexer::{FuncType, Lexer},
+    math,
+    misc::u8_as_i8,
+    vm::{self, VirtualMachine},
+};
+
+// largest power of 10 that fits in an f64 mantissa
+const MAX_DECIMAL_DIGITS: u32 = 15;
+
+/// A reduced fraction with a positive denominator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    numerator: i64,
+    denominator: i64,
+}
+
+impl Rational {
+    /// `None` if `denominator` is zero or the reduced fraction does not fit in `i64`.
+    pub fn new(numerator: i64, denominator: i64) -> Option<Self> {
+        Self::reduce(numerator as i128, denominator as i128)
+    }
+
+    pub fn numerator(&self) -> i64 {
+        self.numerator
+    }
+
+    pub fn denominator(&self) -> i64 {
+        self.denominator
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.numerator as f64 / self.denominator as f64
+    }
+
+    fn reduce(numerator: i128, denominator: i128) -> Option<Self> {
+        if denominator == 0 {
+            return None;
+        }
+        let divisor = gcd(numerator.unsigned_abs(), denominator.unsigned_abs()) as i128;
+        let sign = denominator.signum();
+        Some(Self {
+            numerator: i64::try_from(sign * numerator / divisor).ok()?,
+            denominator: i64::try_from(sign * denominator / divisor).ok()?,
+        })
+    }
+
+    /// The shortest decimal fraction that converts back to `x`, so the literal `0.1` is `1/10`.
+    fn from_decimal(x: f64) -> Option<Self> {
+        (0..=MAX_DECIMAL_DIGITS).find_map(|digits| {
+            let scale = 10i64.pow(digits);
+            let scaled = math::round(x * scale as f64);
+            if scaled.abs() < i64::MAX as f64 && scaled / scale as f64 == x {
+                Self::new(scaled as i64, scale)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn checked_add(self, rhs: Self) -> Option<Self> {
+        Self::reduce(
+            self.numerator as i128 * rhs.denominator as i128
+                + rhs.numerator as i128 * self.denominator as i128,
+            self.denominator as i128 * rhs.denominator as i128,
+        )
+    }
+
+    fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.checked_add(rhs.checked_neg()?)
+    }
+
+    fn checked_mul(self, rhs: Self) -> Option<Self> {
+        Self::reduce(
+            self.numerator as i128 * rhs.numerator as i128,
+            self.denominator as i128 * rhs.denominator as i128,
+        )
+    }
+
+    fn checked_div(self, rhs: Self) -> Option<Self> {
+        Self::reduce(
+            self.numerator as i128 * rhs.denominator as i128,
+            self.denominator as i128 * rhs.numerator as i128,
+        )
+    }
+
+    fn checked_neg(self) -> Option<Self> {
+        Some(Self {
+            numerator: self.numerator.checked_neg()?,
+            denominator: self.denominator,
+        })
+    }
+}
+
+impl From<i64> for Rational {
+    fn from(value: i64) -> Self {
+        Self {
+            numerator: value,
+            denominator: 1,
+        }
+    }
+}
+
+impl Display for Rational {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.denominator == 1 {
+            write!(f, "{}", self.numerator)
+        } else {
+            write!(f, "{}/{}", self.numerator, self.denominator)
+        }
+    }
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Exact(Rational),
+    Float(f64),
+}
+
+impl Value {
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Self::Exact(r) => r.to_f64(),
+            Self::Float(x) => *x,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Rational::from_decimal(value).map_or(Self::Float(value), Self::Exact)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Exact(r) => write!(f, "{}", r),
+            Self::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Compiler(compiler::Error),
+    VirtualMachine(vm::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Compiler(e) => write!(f, "compiler error: {}", e),
+            Self::VirtualMachine(e) => write!(f, "virtual machine error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl From<compiler::Error> for Error {
+    fn from(value: compiler::Error) -> Self {
+        Self::Compiler(value)
+    }
+}
+
+impl From<vm::Error> for Error {
+    fn from(value: vm::Error) -> Self {
+        Self::VirtualMachine(value)
+    }
+}
+
+/// Compile and evaluate `src` keeping fractions exact where possible.
+/// `ans` is not available since no previous result is kept.
+pub fn eval_rational(src: &[u8]) -> Result<Value, Error> {
+    let mut lexer = Lexer::new(src);
+    let mut compiler = Compiler::default();
+    compiler.compile(&mut lexer)?;
+    interpret(compiler.opcodes()).map_err(Error::from)
+}
+
+fn interpret(opcodes: &[u8]) -> Result<Value, vm::Error> {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut ip = 0;
+    while ip < opcodes.len() {
+        let byte = opcodes[ip];
+        ip += 1;
+        let op = Op::try_from(byte).map_err(|_| vm::Error::InvalidOpcode(byte))?;
+        let value = match op {
+            Op::Number => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(operands(opcodes, &mut ip, 8)?);
+                Value::from(read_f64_operand(&bytes))
+            }
+            Op::NumberI8 => {
+                let byte = operands(opcodes, &mut ip, 1)?[0];
+                Value::Exact(Rational::from(u8_as_i8(byte) as i64))
+            }
+            Op::Negate => match stack_pop(&mut stack)? {
+                Value::Exact(r) => r
+                    .checked_neg()
+                    .map_or(Value::Float(-r.to_f64()), Value::Exact),
+                Value::Float(x) => Value::Float(-x),
+            },
+            Op::Plus | Op::Minus | Op::Mult | Op::Div => {
+                let rhs = stack_pop(&mut stack)?;
+                let lhs = stack_pop(&mut stack)?;
+                binary(op, lhs, rhs)?
+            }
+            Op::Percent => {
+                let x = stack_pop(&mut stack)?;
+                binary(Op::Div, x, Value::Exact(Rational::from(100)))?
+            }
+            Op::PercentOf => {
+                let fraction = stack_pop(&mut stack)?;
+                let base = *stack.last().ok_or(vm::Error::StackUnderflow)?;
+                binary(Op::Mult, base, fraction)?
+            }
+            Op::BitAnd | Op::BitOr | Op::ShiftLeft | Op::ShiftRight => {
+                let rhs = stack_pop(&mut stack)?;
+                let lhs = stack_pop(&mut stack)?;
+                // the operands are integers, and so is the result
+                Value::from(eval_float(&[lhs, rhs], &[op.into()])?)
+            }
+            Op::Const => {
+                let constant = operands(opcodes, &mut ip, 1)?[0];
+                Value::Float(eval_float(&[], &[byte, constant])?)
+            }
+            Op::Func => {
+                let code = operands(opcodes, &mut ip, 1)?[0];
+                let func_type =
+                    FuncType::try_from(code).map_err(|_| vm::Error::InvalidFunctionCode(code))?;
+                let count = if func_type.is_variadic() {
+                    operands(opcodes, &mut ip, 1)?[0] as usize
+                } else {
+                    func_type.arity()
+                };
+                let start = stack
+                    .len()
+                    .checked_sub(count)
+                    .ok_or(vm::Error::StackUnderflow)?;
+                let args = stack.split_off(start);
+                function(func_type, &args, &[byte, code])?
+            }
+            Op::Ans | Op::AnsN => return Err(vm::Error::AnsNotAvailable),
+            Op::Halt => break,
+            // the float machine converts to its default radians
+            Op::Degrees | Op::Radians => {
+                let angle = stack_pop(&mut stack)?;
+                Value::Float(eval_float(&[angle], &[op.into()])?)
+            }
+            // there is no `ans` here, the value is dropped
+            Op::SetAns => {
+                stack_pop(&mut stack)?;
+                continue;
+            }
+            // only the float machine has registered functions
+            Op::CallExtern => return Err(vm::Error::UnknownExternFunction),
+        };
+        stack.push(value);
+    }
+    stack.pop().ok_or(vm::Error::EmptyStack)
+}
+
+fn stack_pop(stack: &mut Vec<Value>) -> Result<Value, vm::Error> {
+    stack.pop().ok_or(vm::Error::StackUnderflow)
+}
+
+/// The `n` operand bytes of the instruction before `ip`, moving `ip` past them.
+fn operands<'a>(opcodes: &'a [u8], ip: &mut usize, n: usize) -> Result<&'a [u8], vm::Error> {
+    let bytes = opcodes.get(*ip..*ip + n).ok_or(vm::Error::TruncatedChunk)?;
+    *ip += n;
+    Ok(bytes)
+}
+
+fn binary(op: Op, lhs: Value, rhs: Value) -> Result<Value, vm::Error> {
+    if let (Value::Exact(a), Value::Exact(b)) = (lhs, rhs) {
+        let exact = match op {
+            Op::Plus => a.checked_add(b),
+            Op::Minus => a.checked_sub(b),
+            Op::Mult => a.checked_mul(b),
+            Op::Div => a.checked_div(b),
+            _ => None,
+        };
+        if let Some(r) = exact {
+            return Ok(Value::Exact(r));
+        }
+    }
+    // a float operand, an overflow or a division by zero, which the float path reports
+    eval_float(&[lhs, rhs], &[op.into()]).map(Value::Float)
+}
+
+fn function(func_type: FuncType, args: &[Value], code: &[u8]) -> Result<Value, vm::Error> {
+    let exact: Option<Vec<Rational>> = args
+        .iter()
+        .map(|arg| match arg {
+            Value::Exact(r) => Some(*r),
+            Value::Float(_) => None,
+        })
+        .collect();
+    let exact = exact.and_then(|args| match func_type {
+        FuncType::Abs if args[0].numerator < 0 => args[0].checked_neg(),
+        FuncType::Abs => Some(args[0]),
+        FuncType::Min => args.into_iter().min_by(|a, b| compare(*a, *b)),
+        FuncType::Max => args.into_iter().max_by(|a, b| compare(*a, *b)),
+        _ => None,
+    });
+    match exact {
+        Some(r) => Ok(Value::Exact(r)),
+        None => eval_float(args, code).map(Value::Float),
+    }
+}
+
+fn compare(a: Rational, b: Rational) -> core::cmp::Ordering {
+    (a.numerator as i128 * b.denominator as i128)
+        .cmp(&(b.numerator as i128 * a.denominator as i128))
+}
+
+/// Run `code` on the float virtual machine with `args` pushed first.
+fn eval_float(args: &[Value], code: &[u8]) -> Result<f64, vm::Error> {
+    let mut chunk = Vec::with_capacity(args.len() * 9 + code.len());
+    for arg in args {
+        chunk.push(Op::Number.into());
+        chunk.extend_from_slice(&write_f64_operand(arg.to_f64()));
+    }
+    chunk.extend_from_slice(code);
+    if code.first() == Some(&Op::Func.into())
+        && FuncType::try_from(code[1]).is_ok_and(|f| f.is_variadic())
+    {
+        chunk.push(args.len() as u8);
+    }
+    VirtualMachine::default().interpret(&chunk)
+}
+
+#[cfg(test)]
+mod rational_tests {
+    use super::*;
+
+    fn exact(numerator: i64, denominator: i64) -> Value {
+        Value::Exact(Rational::new(numerator, denominator).unwrap())
+    }
+
+    #[test]
+    fn test_reduce() {
+        let r = Rational::new(4, -6).unwrap();
+        assert_eq!((r.numerator(), r.denominator()), (-2, 3));
+        assert_eq!(Rational::new(1, 0), None);
+    }
+
+    #[test]
+    fn test_exact_fractions() {
+        assert_eq!(eval_rational(b"1/3 + 1/6"), Ok(exact(1, 2)));
+        assert_eq!(eval_rational(b"0.1 + 0.2"), Ok(exact(3, 10)));
+        assert_eq!(eval_rational(b"-(2/4) * 3"), Ok(exact(-3, 2)));
+    }
+
+    #[test]
+    fn test_exact_functions() {
+        assert_eq!(eval_rational(b"|1/3 - 1/2|"), Ok(exact(1, 6)));
+        assert_eq!(eval_rational(b"max(1/3, 2/7, 1/4)"), Ok(exact(1, 3)));
+        assert_eq!(eval_rational(b"50%"), Ok(exact(1, 2)));
+    }
+
+    #[test]
+    fn test_float_fallback() {
+        assert_eq!(eval_rational(b"sqrt(4) + 1/2"), Ok(Value::Float(2.5)));
+        assert_eq!(eval_rational(b"min(sqrt(4), 3)"), Ok(Value::Float(2.0)));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            eval_rational(b"1/(1/2 - 1/2)"),
+            Err(Error::VirtualMachine(vm::Error::DivisionByZero))
+        );
+        assert_eq!(
+            eval_rational(b"ans"),
+            Err(Error::VirtualMachine(vm::Error::AnsNotAvailable))
+        );
+    }
+
+    #[test]
+    fn test_malformed_chunks() {
+        assert_eq!(interpret(&[255]), Err(vm::Error::InvalidOpcode(255)));
+        assert_eq!(
+            interpret(&[Op::Number.into(), 0, 0]),
+            Err(vm::Error::TruncatedChunk)
+        );
+        assert_eq!(
+            interpret(&[Op::NumberI8.into()]),
+            Err(vm::Error::TruncatedChunk)
+        );
+        assert_eq!(
+            interpret(&[Op::Func.into(), FuncType::Max.into()]),
+            Err(vm::Error::TruncatedChunk)
+        );
+        assert_eq!(
+            interpret(&[Op::Func.into(), 255]),
+            Err(vm::Error::InvalidFunctionCode(255))
+        );
+        assert_eq!(
+            interpret(&[
+                Op::NumberI8.into(),
+                1,
+                Op::Func.into(),
+                FuncType::Max.into(),
+                3
+            ]),
+            Err(vm::Error::StackUnderflow)
+        );
+        assert_eq!(
+            interpret(&[Op::NumberI8.into(), 1, Op::Plus.into()]),
+            Err(vm::Error::StackUnderflow)
+        );
+        assert_eq!(
+            interpret(&[Op::NumberI8.into(), 1, Op::PercentOf.into()]),
+            Err(vm::Error::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            Error::VirtualMachine(vm::Error::DivisionByZero).to_string(),
+            "virtual machine error: division by zero"
+        );
+        assert_eq!(
+            Error::Compiler(compiler::Error::EmptyExpression).to_string(),
+            "compiler error: empty expression"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(exact(6, 4).to_string(), "3/2");
+        assert_eq!(exact(6, 3).to_string(), "2");
+    }
+}
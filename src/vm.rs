@@ -1,8 +1,34 @@
-use std::fmt::Display;
-
-use crate::{compiler::Op, lexer::FuncType, misc::u8_as_i8};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Display;
+
+use crate::{
+    compiler::{read_f64_operand, Op},
+    lexer::{Constant, FuncType},
+    math,
+    misc::u8_as_i8,
+};
 
 const STACK_INITIAL_CAPACITY: usize = 256;
+const ANS_HISTORY_LEN: usize = 16;
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+const MAX_ROUND_DIGITS: f64 = 15.0;
+
+/// A function registered with `VirtualMachine::register_fn`.
+pub type ExternFn = Box<dyn Fn(&[f64]) -> Result<f64, Error>>;
+
+/// Unit of the argument of `sin`, `cos`, `sec`, `csc` and `cot`, and of the
+/// result of `asin`, `acos`, `atan` and `atan2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FuncArgs {
@@ -19,20 +45,112 @@ pub enum Error {
         func_args: FuncArgs,
     },
     AnsNotAvailable,
+    InvalidOperands {
+        op: Op,
+        lhs: f64,
+        rhs: f64,
+    },
+    /// An instruction is missing some of its operand bytes.
+    TruncatedChunk,
+    InvalidOpcode(u8),
+    InvalidFunctionCode(u8),
+    InvalidConstantCode(u8),
+    /// An instruction found fewer operands on the stack than it needs.
+    StackUnderflow,
+    /// The step budget ran out. The machine is left as it was, see `resume`.
+    StepLimitExceeded,
+    /// `Op::CallExtern` names a function that was not registered.
+    UnknownExternFunction,
+    ExternArity {
+        expected: usize,
+        found: usize,
+    },
+    /// An instruction produced `inf` or `NaN`, with `with_strict_nonfinite(true)`.
+    NonFiniteResult(f64),
+}
+
+impl Error {
+    /// Stable machine-readable identifier of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::DivisionByZero => "E_DIV_ZERO",
+            Self::EmptyStack => "E_EMPTY_STACK",
+            Self::InvalidFunctionArgs { .. } => "E_INVALID_FUNCTION_ARGS",
+            Self::AnsNotAvailable => "E_ANS_NOT_AVAILABLE",
+            Self::InvalidOperands { .. } => "E_INVALID_OPERANDS",
+            Self::TruncatedChunk => "E_TRUNCATED_CHUNK",
+            Self::InvalidOpcode(_) => "E_INVALID_OPCODE",
+            Self::InvalidFunctionCode(_) => "E_INVALID_FUNCTION_CODE",
+            Self::InvalidConstantCode(_) => "E_INVALID_CONSTANT_CODE",
+            Self::StepLimitExceeded => "E_STEP_LIMIT",
+            Self::StackUnderflow => "E_STACK_UNDERFLOW",
+            Self::UnknownExternFunction => "E_UNKNOWN_EXTERN_FUNCTION",
+            Self::ExternArity { .. } => "E_EXTERN_ARITY",
+            Self::NonFiniteResult(_) => "E_NON_FINITE_RESULT",
+        }
+    }
 }
 
 impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::DivisionByZero => f.write_str("division by zero"),
+            Self::EmptyStack => f.write_str("no result on the stack"),
+            Self::InvalidFunctionArgs {
+                func_type,
+                func_args,
+            } => match func_args {
+                FuncArgs::Arg1(x) => write!(f, "invalid argument for {}: {}", func_type.name(), x),
+                FuncArgs::Arg2(x, y) => {
+                    write!(
+                        f,
+                        "invalid arguments for {}: {}, {}",
+                        func_type.name(),
+                        x,
+                        y
+                    )
+                }
+            },
+            Self::AnsNotAvailable => f.write_str("no previous result for 'ans'"),
+            Self::InvalidOperands { op, lhs, rhs } => {
+                write!(f, "invalid operands for {}: {}, {}", op, lhs, rhs)
+            }
+            Self::TruncatedChunk => f.write_str("truncated bytecode"),
+            Self::InvalidOpcode(byte) => write!(f, "invalid opcode {}", byte),
+            Self::InvalidFunctionCode(byte) => write!(f, "invalid function code {}", byte),
+            Self::InvalidConstantCode(byte) => write!(f, "invalid constant code {}", byte),
+            Self::StackUnderflow => f.write_str("stack underflow"),
+            Self::StepLimitExceeded => f.write_str("step limit exceeded"),
+            Self::UnknownExternFunction => f.write_str("unknown function"),
+            Self::ExternArity { expected, found } => write!(
+                f,
+                "function takes {} argument(s), found {}",
+                expected, found
+            ),
+            Self::NonFiniteResult(x) => write!(f, "result is not finite: {}", x),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
 pub struct VirtualMachine {
     instruction_pointer: usize,
     stack: Vec<f64>,
     ans: Option<f64>,
+    // results of the `;` separated statements of the current program, committed
+    // to `ans` and the history only once it succeeds
+    statement_results: Vec<f64>,
+    last_result: Option<f64>,
+    // most recent result first
+    history: VecDeque<f64>,
+    div_epsilon: f64,
+    strict_nonfinite: bool,
+    rng_state: u64,
+    step_limit: Option<usize>,
+    angle_mode: AngleMode,
+    externs: BTreeMap<String, (usize, ExternFn)>,
 }
 
 impl Default for VirtualMachine {
@@ -41,37 +159,294 @@ impl Default for VirtualMachine {
             instruction_pointer: 0,
             stack: Vec::with_capacity(STACK_INITIAL_CAPACITY),
             ans: None,
+            statement_results: Vec::new(),
+            last_result: None,
+            history: VecDeque::with_capacity(ANS_HISTORY_LEN),
+            div_epsilon: 0.0,
+            strict_nonfinite: false,
+            rng_state: DEFAULT_SEED,
+            step_limit: None,
+            angle_mode: AngleMode::default(),
+            externs: BTreeMap::new(),
         }
     }
 }
 
 impl VirtualMachine {
+    /// Treat divisors with `|divisor| < epsilon` as zero. The default `0.0` only
+    /// rejects an exact zero.
+    pub fn with_div_epsilon(mut self, epsilon: f64) -> Self {
+        self.div_epsilon = epsilon;
+        self
+    }
+
+    pub fn div_epsilon(&self) -> f64 {
+        self.div_epsilon
+    }
+
+    /// Fail with `Error::NonFiniteResult` as soon as an instruction pushes `inf`
+    /// or `NaN`, instead of carrying it to the result. Off by default.
+    pub fn with_strict_nonfinite(mut self, strict_nonfinite: bool) -> Self {
+        self.strict_nonfinite = strict_nonfinite;
+        self
+    }
+
+    pub fn strict_nonfinite(&self) -> bool {
+        self.strict_nonfinite
+    }
+
+    /// Stop `interpret` with `Error::StepLimitExceeded` after this many
+    /// instructions. `None`, the default, means no limit.
+    pub fn with_step_limit(mut self, step_limit: Option<usize>) -> Self {
+        self.step_limit = step_limit;
+        self
+    }
+
+    pub fn step_limit(&self) -> Option<usize> {
+        self.step_limit
+    }
+
+    /// Preallocate room for `capacity` values instead of the default 256, e.g.
+    /// `Compiler::max_stack_depth` of the programs to run.
+    pub fn with_stack_capacity(mut self, capacity: usize) -> Self {
+        self.stack = Vec::with_capacity(capacity);
+        self
+    }
+
+    pub fn with_angle_mode(mut self, angle_mode: AngleMode) -> Self {
+        self.angle_mode = angle_mode;
+        self
+    }
+
+    /// Change the angle mode of a machine in use, keeping `ans` and the history.
+    pub fn set_angle_mode(&mut self, angle_mode: AngleMode) {
+        self.angle_mode = angle_mode;
+    }
+
+    pub fn angle_mode(&self) -> AngleMode {
+        self.angle_mode
+    }
+
+    /// Make `name` callable with `arity` arguments. Scan with
+    /// `Lexer::with_extern_names(&vm.extern_names())` so the compiler sees it.
+    /// Registering a name again replaces the function.
+    pub fn register_fn(
+        &mut self,
+        name: impl Into<String>,
+        arity: usize,
+        func: impl Fn(&[f64]) -> Result<f64, Error> + 'static,
+    ) {
+        self.externs.insert(name.into(), (arity, Box::new(func)));
+    }
+
+    pub fn extern_names(&self) -> Vec<&str> {
+        self.externs.keys().map(String::as_str).collect()
+    }
+
+    fn to_radians(&self, angle: f64) -> f64 {
+        match self.angle_mode {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_radians(),
+        }
+    }
+
+    /// Sine and cosine of an angle in the current mode. In degrees the multiples
+    /// of 90° are exact, as converting them to radians leaves the zeros at a
+    /// tiny distance from `0.0`.
+    fn sin_cos(&self, angle: f64) -> (f64, f64) {
+        if self.angle_mode == AngleMode::Degrees && angle % 90.0 == 0.0 {
+            return match (angle / 90.0) % 4.0 {
+                q if q == 1.0 || q == -3.0 => (1.0, 0.0),
+                q if q == 2.0 || q == -2.0 => (0.0, -1.0),
+                q if q == 3.0 || q == -1.0 => (-1.0, 0.0),
+                _ => (0.0, 1.0),
+            };
+        }
+        let angle = self.to_radians(angle);
+        (math::sin(angle), math::cos(angle))
+    }
+
+    /// The inverse of `to_radians`, for the results of inverse functions.
+    fn to_angle_mode(&self, angle: f64) -> f64 {
+        match self.angle_mode {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_degrees(),
+        }
+    }
+
+    /// Like `to_angle_mode`, for an angle in degrees.
+    fn degrees_to_angle_mode(&self, angle: f64) -> f64 {
+        match self.angle_mode {
+            AngleMode::Radians => angle.to_radians(),
+            AngleMode::Degrees => angle,
+        }
+    }
+
+    /// A machine whose `rand()` sequence is fully determined by `seed`.
+    /// The default one always uses the same seed.
+    pub fn new_seeded(seed: u64) -> Self {
+        Self {
+            // xorshift never leaves the all zeros state
+            rng_state: if seed == 0 { DEFAULT_SEED } else { seed },
+            ..Self::default()
+        }
+    }
+
+    /// Seed the value of `ans`. Unlike `reset` nothing else is touched, so
+    /// this is meant for a freshly built machine.
+    pub fn with_ans(mut self, ans: Option<f64>) -> Self {
+        self.ans = ans;
+        self
+    }
+
+    /// The value `ans` evaluates to before a program runs. Besides `with_ans` and
+    /// `reset`, it only changes when a program with several `;` separated
+    /// statements succeeds, to the result of its second to last statement. A
+    /// failing program leaves it untouched.
+    pub fn ans(&self) -> Option<f64> {
+        self.ans
+    }
+
+    /// Clear the stack and instruction pointer to run a new program, with `ans`
+    /// as the previous result.
     pub fn reset(&mut self, ans: Option<f64>) {
         self.instruction_pointer = 0;
         self.stack.clear();
+        self.statement_results.clear();
         self.ans = ans;
     }
 
+    /// Run a program from its start. Whatever a previous run left on the stack
+    /// is discarded, so calling this twice gives the same result; `ans` and the
+    /// history are kept.
     pub fn interpret(&mut self, opcodes: &[u8]) -> Result<f64, Error> {
-        while self.instruction_pointer < opcodes.len() {
-            let byte = self.advance_instruction(opcodes);
-            let op = Op::try_from(byte)
-                .unwrap_or_else(|e| panic!("Invalid opcode {}, error: {:?}", byte, e));
-            match op {
-                Op::Number => self.number(opcodes),
-                Op::NumberI8 => self.number_i8(opcodes),
-                Op::Negate => self.negate(),
-                Op::Minus | Op::Plus | Op::Mult | Op::Div => self.binary(op)?,
-                Op::Func => self.function(opcodes)?,
-                Op::Ans => self.load_ans()?,
-            };
+        self.instruction_pointer = 0;
+        self.stack.clear();
+        self.statement_results.clear();
+        self.run(opcodes)
+    }
+
+    /// Continue a program stopped by `Error::StepLimitExceeded`, with a fresh
+    /// step budget. The stack and instruction pointer are kept from where it stopped.
+    pub fn resume(&mut self, opcodes: &[u8]) -> Result<f64, Error> {
+        self.run(opcodes)
+    }
+
+    fn run(&mut self, opcodes: &[u8]) -> Result<f64, Error> {
+        let mut steps = 0;
+        loop {
+            if self.step_limit.is_some_and(|limit| steps >= limit) {
+                return Err(Error::StepLimitExceeded);
+            }
+            if let Some(res) = self.step(opcodes)? {
+                return Ok(res);
+            }
+            steps += 1;
+        }
+    }
+
+    /// Offset of the next instruction to execute.
+    pub fn instruction_pointer(&self) -> usize {
+        self.instruction_pointer
+    }
+
+    /// Execute a single instruction. Returns `None` while there are instructions
+    /// left and the result once the last one has run.
+    pub fn step(&mut self, opcodes: &[u8]) -> Result<Option<f64>, Error> {
+        if self.instruction_pointer < opcodes.len() {
+            self.execute(opcodes)?;
+            if self.instruction_pointer < opcodes.len() {
+                return Ok(None);
+            }
         }
         // reset for further calls
-        self.stack.pop().ok_or(Error::EmptyStack)
+        let res = self.stack.pop().ok_or(Error::EmptyStack)?;
+        if let Some(&ans) = self.statement_results.last() {
+            self.ans = Some(ans);
+        }
+        for statement in core::mem::take(&mut self.statement_results) {
+            self.record_result(statement);
+        }
+        self.record_result(res);
+        Ok(Some(res))
+    }
+
+    fn record_result(&mut self, res: f64) {
+        self.last_result = Some(res);
+        if self.history.len() == ANS_HISTORY_LEN {
+            self.history.pop_back();
+        }
+        self.history.push_front(res);
+    }
+
+    /// Run the whole program and return everything left on the stack, top last.
+    /// Unlike `interpret` no result is recorded for `last_result` or `ans(n)`.
+    pub fn interpret_all(&mut self, opcodes: &[u8]) -> Result<Vec<f64>, Error> {
+        self.instruction_pointer = 0;
+        self.stack.clear();
+        while self.instruction_pointer < opcodes.len() {
+            self.execute(opcodes)?;
+        }
+        Ok(self.stack.drain(..).collect())
+    }
+
+    fn execute(&mut self, opcodes: &[u8]) -> Result<(), Error> {
+        let byte = self.advance_instruction(opcodes)?;
+        let op = Op::try_from(byte).map_err(|_| Error::InvalidOpcode(byte))?;
+        match op {
+            Op::Number => self.number(opcodes)?,
+            Op::NumberI8 => self.number_i8(opcodes)?,
+            Op::Negate => self.negate()?,
+            Op::Minus | Op::Plus | Op::Mult | Op::Div => self.binary(op)?,
+            Op::BitAnd | Op::BitOr | Op::ShiftLeft | Op::ShiftRight => self.bitwise(op)?,
+            Op::Func => self.function(opcodes)?,
+            Op::Ans => self.load_ans()?,
+            Op::AnsN => self.load_ans_n(opcodes)?,
+            Op::Percent => self.percent()?,
+            Op::PercentOf => self.percent_of()?,
+            Op::Const => self.constant(opcodes)?,
+            Op::Halt => self.instruction_pointer = opcodes.len(),
+            Op::CallExtern => self.call_extern(opcodes)?,
+            Op::Degrees => {
+                let angle = self.stack_pop()?;
+                self.stack.push(self.degrees_to_angle_mode(angle));
+            }
+            Op::Radians => {
+                let angle = self.stack_pop()?;
+                self.stack.push(self.to_angle_mode(angle));
+            }
+            Op::SetAns => {
+                let res = self.stack_pop()?;
+                self.statement_results.push(res);
+            }
+        };
+        if self.strict_nonfinite {
+            if let Some(&top) = self.stack.last().filter(|top| !top.is_finite()) {
+                return Err(Error::NonFiniteResult(top));
+            }
+        }
+        Ok(())
+    }
+
+    /// The operands currently on the stack, bottom first.
+    pub fn stack_snapshot(&self) -> &[f64] {
+        &self.stack
+    }
+
+    /// The result of the most recent successful `interpret`, kept across `reset`.
+    pub fn last_result(&self) -> Option<f64> {
+        self.last_result
+    }
+
+    /// Whether `last_result` is a whole number, e.g. to print it without an
+    /// exponent. Values are not tracked as integers, so `0.5 * 4` counts too.
+    pub fn last_was_integer(&self) -> bool {
+        self.last_result
+            .is_some_and(|r| r.is_finite() && math::fract(r) == 0.0)
     }
 
     fn load_ans(&mut self) -> Result<(), Error> {
-        match self.ans {
+        match self.statement_results.last().copied().or(self.ans) {
             Some(ans) => {
                 self.stack.push(ans);
                 Ok(())
@@ -80,14 +455,59 @@ impl VirtualMachine {
         }
     }
 
+    /// Unlike `ans`, which is whatever was passed to `reset`, the indexed history holds
+    /// the results of the last successful `interpret` calls and survives `reset`.
+    /// The statements already run in the current program come first.
+    fn load_ans_n(&mut self, opcodes: &[u8]) -> Result<(), Error> {
+        let index = self.advance_instruction(opcodes)? as usize;
+        let pending = self.statement_results.len();
+        let ans = match index.checked_sub(1) {
+            Some(i) if i < pending => self.statement_results.get(pending - 1 - i),
+            Some(i) => self.history.get(i - pending),
+            None => None,
+        };
+        match ans {
+            Some(&ans) => {
+                self.stack.push(ans);
+                Ok(())
+            }
+            None => Err(Error::AnsNotAvailable),
+        }
+    }
+
+    fn call_extern(&mut self, opcodes: &[u8]) -> Result<(), Error> {
+        let count = self.advance_instruction(opcodes)? as usize;
+        let len = self.advance_instruction(opcodes)? as usize;
+        let name = self.advance_instruction_by(opcodes, len)?;
+        let (arity, func) = core::str::from_utf8(name)
+            .ok()
+            .and_then(|name| self.externs.get(name))
+            .ok_or(Error::UnknownExternFunction)?;
+        if count != *arity {
+            return Err(Error::ExternArity {
+                expected: *arity,
+                found: count,
+            });
+        }
+        let first = self
+            .stack
+            .len()
+            .checked_sub(count)
+            .ok_or(Error::StackUnderflow)?;
+        let res = func(&self.stack[first..])?;
+        self.stack.truncate(first);
+        self.stack.push(res);
+        Ok(())
+    }
+
     fn function(&mut self, opcodes: &[u8]) -> Result<(), Error> {
-        let func_type = self.advance_instruction(opcodes);
-        let func_type = FuncType::try_from(func_type)
-            .unwrap_or_else(|e| panic!("Invalid byte function code {:?}", e));
+        let func_type = self.advance_instruction(opcodes)?;
+        let func_type =
+            FuncType::try_from(func_type).map_err(|_| Error::InvalidFunctionCode(func_type))?;
         match func_type {
             FuncType::Log => {
-                let arg = self.stack_pop("Missing function argument (Log)");
-                let val = arg.ln();
+                let arg = self.stack_pop()?;
+                let val = math::ln(arg);
                 if !val.is_finite() {
                     return Err(Error::InvalidFunctionArgs {
                         func_type,
@@ -97,16 +517,67 @@ impl VirtualMachine {
                 self.stack.push(val);
             }
             FuncType::Sin => {
-                let arg = self.stack_pop("Missing function argument (Log)");
-                self.stack.push(arg.sin());
+                let arg = self.stack_pop()?;
+                self.stack.push(math::sin(self.to_radians(arg)));
             }
             FuncType::Cos => {
-                let arg = self.stack_pop("Missing function argument (Log)");
-                self.stack.push(arg.cos());
+                let arg = self.stack_pop()?;
+                self.stack.push(math::cos(self.to_radians(arg)));
+            }
+            FuncType::Sec | FuncType::Csc | FuncType::Cot => {
+                let arg = self.stack_pop()?;
+                let (sin, cos) = self.sin_cos(arg);
+                let (numerator, denominator) = match func_type {
+                    FuncType::Sec => (1.0, cos),
+                    FuncType::Csc => (1.0, sin),
+                    _ => (cos, sin),
+                };
+                if denominator == 0.0 {
+                    return Err(Error::InvalidFunctionArgs {
+                        func_type,
+                        func_args: FuncArgs::Arg1(arg),
+                    });
+                }
+                self.stack.push(numerator / denominator);
+            }
+            FuncType::Abs => {
+                let arg = self.stack_pop()?;
+                self.stack.push(arg.abs());
+            }
+            FuncType::Asin | FuncType::Acos => {
+                let arg = self.stack_pop()?;
+                if !(-1.0..=1.0).contains(&arg) {
+                    return Err(Error::InvalidFunctionArgs {
+                        func_type,
+                        func_args: FuncArgs::Arg1(arg),
+                    });
+                }
+                let angle = match func_type {
+                    FuncType::Asin => math::asin(arg),
+                    _ => math::acos(arg),
+                };
+                self.stack.push(self.to_angle_mode(angle));
+            }
+            FuncType::Atan => {
+                let arg = self.stack_pop()?;
+                self.stack.push(self.to_angle_mode(math::atan(arg)));
+            }
+            // not angle functions, the angle mode does not apply
+            FuncType::Sinh | FuncType::Cosh | FuncType::Tanh => {
+                let arg = self.stack_pop()?;
+                self.stack.push(match func_type {
+                    FuncType::Sinh => math::sinh(arg),
+                    FuncType::Cosh => math::cosh(arg),
+                    _ => math::tanh(arg),
+                });
+            }
+            FuncType::Atan2 => {
+                let (y, x) = self.pop2()?;
+                self.stack.push(self.to_angle_mode(math::atan2(y, x)));
             }
             FuncType::Sqrt => {
-                let arg = self.stack_pop("Missing function argument (Log)");
-                let val = arg.sqrt();
+                let arg = self.stack_pop()?;
+                let val = math::sqrt(arg);
                 if val.is_nan() {
                     return Err(Error::InvalidFunctionArgs {
                         func_type,
@@ -115,64 +586,215 @@ impl VirtualMachine {
                 }
                 self.stack.push(val);
             }
+            FuncType::Ssqrt => {
+                let arg = self.stack_pop()?;
+                let root = math::sqrt(arg.abs());
+                self.stack.push(if arg < 0.0 { -root } else { root });
+            }
+            // unnormalized and always in radians, as it is not an angle function
+            FuncType::Sinc => {
+                let arg = self.stack_pop()?;
+                self.stack.push(if arg == 0.0 {
+                    1.0
+                } else {
+                    math::sin(arg) / arg
+                });
+            }
+            FuncType::Fma => {
+                let c = self.stack_pop()?;
+                let (a, b) = self.pop2()?;
+                self.stack.push(math::mul_add(a, b, c));
+            }
+            FuncType::Saturate => {
+                let arg = self.stack_pop()?;
+                self.stack.push(arg.clamp(0.0, 1.0));
+            }
+            FuncType::Step => {
+                let arg = self.stack_pop()?;
+                self.stack.push(if arg < 0.0 { 0.0 } else { 1.0 });
+            }
+            FuncType::Rect => {
+                let arg = self.stack_pop()?;
+                self.stack.push(if arg.abs() <= 0.5 { 1.0 } else { 0.0 });
+            }
+            FuncType::Tau => self.stack.push(core::f64::consts::TAU),
+            FuncType::Rand => {
+                let x = self.next_random();
+                self.stack.push(x);
+            }
+            FuncType::Phi => self.stack.push((1.0 + math::sqrt(5.0)) / 2.0),
             FuncType::Pow => {
-                let exponent = self.stack_pop("Missing exponent in pow");
-                let base = self.stack_pop("Missing base in pow");
-                if base < 0.0 && exponent.fract() != 0.0 {
+                let (base, exponent) = self.pop2()?;
+                if base < 0.0 && math::fract(exponent) != 0.0 {
                     return Err(Error::InvalidFunctionArgs {
                         func_type,
                         func_args: FuncArgs::Arg2(base, exponent),
                     });
                 }
-                self.stack.push(base.powf(exponent));
+                // `powi` is exact for small integer exponents, `powf` may be off by an ulp
+                let is_i32 = (i32::MIN as f64..=i32::MAX as f64).contains(&exponent);
+                let res = if math::fract(exponent) == 0.0 && is_i32 {
+                    math::powi(base, exponent as i32)
+                } else {
+                    math::powf(base, exponent)
+                };
+                self.stack.push(res);
+            }
+            FuncType::Min | FuncType::Max => {
+                let count = self.advance_instruction(opcodes)?;
+                let reduce = match func_type {
+                    FuncType::Min => f64::min,
+                    _ => f64::max,
+                };
+                let mut val = None;
+                for _ in 0..count {
+                    let arg = self.stack_pop()?;
+                    val = Some(val.map_or(arg, |v| reduce(v, arg)));
+                }
+                self.stack.push(val.ok_or(Error::StackUnderflow)?);
+            }
+            FuncType::IDiv => {
+                let (dividend, divisor) = self.pop2()?;
+                if divisor == 0.0 {
+                    return Err(Error::DivisionByZero);
+                }
+                // truncates toward zero: idiv(-7, 2) is -3, whereas floor(-7 / 2) is -4
+                self.stack.push(math::trunc(dividend / divisor));
+            }
+            FuncType::Gcd => {
+                let (a, b) = self.pop2()?;
+                if math::fract(a) != 0.0 || math::fract(b) != 0.0 {
+                    return Err(Error::InvalidFunctionArgs {
+                        func_type,
+                        func_args: FuncArgs::Arg2(a, b),
+                    });
+                }
+                let (mut a, mut b) = (a.abs(), b.abs());
+                while b != 0.0 {
+                    (a, b) = (b, a % b);
+                }
+                self.stack.push(a);
+            }
+            FuncType::PctChange => {
+                let (old, new) = self.pop2()?;
+                if old == 0.0 {
+                    return Err(Error::DivisionByZero);
+                }
+                self.stack.push((new - old) / old * 100.0);
+            }
+            FuncType::PctOf => {
+                let (part, whole) = self.pop2()?;
+                if whole == 0.0 {
+                    return Err(Error::DivisionByZero);
+                }
+                self.stack.push(part / whole * 100.0);
+            }
+            FuncType::RoundTo => {
+                let (x, digits) = self.pop2()?;
+                if math::fract(digits) != 0.0 {
+                    return Err(Error::InvalidFunctionArgs {
+                        func_type,
+                        func_args: FuncArgs::Arg2(x, digits),
+                    });
+                }
+                let digits = digits.clamp(0.0, MAX_ROUND_DIGITS);
+                let scale = math::powi(10.0, digits as i32);
+                self.stack.push(math::round(x * scale) / scale);
             }
         };
         Ok(())
     }
 
+    /// xorshift64, mapped to `[0, 1)` through the top 53 bits.
+    fn next_random(&mut self) -> f64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
     #[inline(always)]
-    fn stack_pop(&mut self, msg: &'static str) -> f64 {
-        self.stack.pop().expect(msg)
+    fn stack_pop(&mut self) -> Result<f64, Error> {
+        self.stack.pop().ok_or(Error::StackUnderflow)
     }
 
-    fn advance_instruction(&mut self, opcodes: &[u8]) -> u8 {
+    /// Pop the two operands of a binary instruction in source order: for `a - b`
+    /// or `pow(a, b)` this is `(a, b)`, although `b` is on top of the stack.
+    #[inline(always)]
+    fn pop2(&mut self) -> Result<(f64, f64), Error> {
+        let second = self.stack_pop()?;
+        let first = self.stack_pop()?;
+        Ok((first, second))
+    }
+
+    fn advance_instruction(&mut self, opcodes: &[u8]) -> Result<u8, Error> {
+        let byte = *opcodes
+            .get(self.instruction_pointer)
+            .ok_or(Error::TruncatedChunk)?;
         self.instruction_pointer += 1;
-        opcodes[self.instruction_pointer - 1]
+        Ok(byte)
     }
 
-    fn advance_instruction_by<'a>(&mut self, opcodes: &'a [u8], n_bytes: usize) -> &'a [u8] {
-        let bytes = &opcodes[self.instruction_pointer..self.instruction_pointer + n_bytes];
+    fn advance_instruction_by<'a>(
+        &mut self,
+        opcodes: &'a [u8],
+        n_bytes: usize,
+    ) -> Result<&'a [u8], Error> {
+        let bytes = opcodes
+            .get(self.instruction_pointer..self.instruction_pointer + n_bytes)
+            .ok_or(Error::TruncatedChunk)?;
         self.instruction_pointer += n_bytes;
-        bytes
+        Ok(bytes)
     }
 
-    fn number(&mut self, opcodes: &[u8]) {
-        let f64_bytes = 8;
-        let bytes = self.advance_instruction_by(opcodes, f64_bytes);
-        debug_assert!(bytes.len() == f64_bytes);
-        let p = bytes.as_ptr();
-        let mut res = 0.0;
-        let pres = std::ptr::from_mut(&mut res) as *mut u8;
-        unsafe { p.copy_to_nonoverlapping(pres, f64_bytes) };
-        self.stack.push(res);
+    fn number(&mut self, opcodes: &[u8]) -> Result<(), Error> {
+        let bytes = self.advance_instruction_by(opcodes, 8)?;
+        let bytes = bytes.try_into().map_err(|_| Error::TruncatedChunk)?;
+        self.stack.push(read_f64_operand(bytes));
+        Ok(())
     }
 
-    fn number_i8(&mut self, opcodes: &[u8]) {
-        let byte = self.advance_instruction(opcodes);
+    fn number_i8(&mut self, opcodes: &[u8]) -> Result<(), Error> {
+        let byte = self.advance_instruction(opcodes)?;
         self.stack.push(u8_as_i8(byte) as f64);
+        Ok(())
     }
 
-    fn negate(&mut self) {
-        let n = self.stack_pop("Empty stack in negate function");
+    fn negate(&mut self) -> Result<(), Error> {
+        let n = self.stack_pop()?;
         self.stack.push(-n);
+        Ok(())
+    }
+
+    fn constant(&mut self, opcodes: &[u8]) -> Result<(), Error> {
+        let code = self.advance_instruction(opcodes)?;
+        let constant = Constant::try_from(code).map_err(|_| Error::InvalidConstantCode(code))?;
+        self.stack.push(constant.value());
+        Ok(())
+    }
+
+    fn percent(&mut self) -> Result<(), Error> {
+        let n = self.stack_pop()?;
+        self.stack.push(n / 100.0);
+        Ok(())
+    }
+
+    /// Turn the fraction on top of the stack into that fraction of the value
+    /// below it, which is left in place for the following `+` or `-`.
+    fn percent_of(&mut self) -> Result<(), Error> {
+        let fraction = self.stack_pop()?;
+        let base = *self.stack.last().ok_or(Error::StackUnderflow)?;
+        self.stack.push(base * fraction);
+        Ok(())
     }
 
     fn binary(&mut self, op: Op) -> Result<(), Error> {
-        let a = self.stack_pop("Empty stack in binary. First operand");
-        let b = self.stack_pop("Empty stack in binary. Second operand");
+        let (b, a) = self.pop2()?;
         let n = match op {
             Op::Div => {
-                if a == 0.0 {
+                if a == 0.0 || a.abs() < self.div_epsilon {
                     return Err(Error::DivisionByZero);
                 }
                 b / a
@@ -185,13 +807,45 @@ impl VirtualMachine {
         self.stack.push(n);
         Ok(())
     }
+
+    fn bitwise(&mut self, op: Op) -> Result<(), Error> {
+        let (b, a) = self.pop2()?;
+        let invalid = Error::InvalidOperands { op, lhs: b, rhs: a };
+        let (lhs, rhs) = match (as_integer(b), as_integer(a)) {
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => return Err(invalid),
+        };
+        let n = match op {
+            Op::BitAnd => lhs & rhs,
+            Op::BitOr => lhs | rhs,
+            Op::ShiftLeft => u32::try_from(rhs)
+                .ok()
+                .and_then(|rhs| lhs.checked_shl(rhs))
+                .ok_or(invalid)?,
+            Op::ShiftRight => u32::try_from(rhs)
+                .ok()
+                .and_then(|rhs| lhs.checked_shr(rhs))
+                .ok_or(invalid)?,
+            _ => panic!("Invalid bitwise operation {:?}", op),
+        };
+        self.stack.push(n as f64);
+        Ok(())
+    }
+}
+
+fn as_integer(n: f64) -> Option<i64> {
+    if math::fract(n) == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod vm_tests {
-    use crate::{assert_float_eq, compiler::Op, lexer::FuncType, misc::i8_as_u8};
+    use crate::{assert_float_eq, compiler::Op, lexer::FuncType, math, misc::i8_as_u8};
 
-    use super::VirtualMachine;
+    use super::{AngleMode, Error, FuncArgs, VirtualMachine};
 
     fn number_to_bytes(n: f64) -> Vec<u8> {
         let as_u64 = n.to_bits();
@@ -407,4 +1061,913 @@ mod vm_tests {
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), n as f64);
     }
+
+    #[test]
+    fn test_function_round_to() {
+        let mut vm = VirtualMachine::default();
+
+        let mut opcodes = vec![Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(1.23456));
+        opcodes.push(Op::NumberI8.into());
+        opcodes.push(i8_as_u8(2));
+        opcodes.push(Op::Func.into());
+        opcodes.push(FuncType::RoundTo.into());
+
+        let res = vm.interpret(&opcodes);
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), 1.23);
+    }
+
+    #[test]
+    fn test_function_round_to_clamps_digits() {
+        let round_to = |x: f64, digits: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(x));
+            opcodes.push(Op::Number.into());
+            opcodes.append(&mut number_to_bytes(digits));
+            opcodes.extend_from_slice(&[Op::Func.into(), FuncType::RoundTo.into()]);
+            VirtualMachine::default().interpret(&opcodes)
+        };
+        assert_eq!(round_to(core::f64::consts::PI, 2.0), Ok(314.0 / 100.0));
+        assert_eq!(round_to(3.6, -2.0), Ok(4.0));
+        assert_eq!(round_to(0.1, 400.0), Ok(0.1));
+    }
+
+    #[test]
+    fn test_gcd() {
+        let gcd = |a: f64, b: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(a));
+            opcodes.push(Op::Number.into());
+            opcodes.append(&mut number_to_bytes(b));
+            opcodes.extend_from_slice(&[Op::Func.into(), FuncType::Gcd.into()]);
+            VirtualMachine::default().interpret(&opcodes)
+        };
+        assert_eq!(gcd(12.0, 18.0), Ok(6.0));
+        assert_eq!(gcd(-4.0, 6.0), Ok(2.0));
+        assert_eq!(gcd(7.0, 0.0), Ok(7.0));
+        assert_eq!(
+            gcd(1.5, 2.0),
+            Err(Error::InvalidFunctionArgs {
+                func_type: FuncType::Gcd,
+                func_args: super::FuncArgs::Arg2(1.5, 2.0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_function_round_to_invalid_digits() {
+        let mut vm = VirtualMachine::default();
+
+        let mut opcodes = vec![Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(1.23456));
+        opcodes.push(Op::Number.into());
+        opcodes.append(&mut number_to_bytes(1.5));
+        opcodes.push(Op::Func.into());
+        opcodes.push(FuncType::RoundTo.into());
+
+        let res = vm.interpret(&opcodes);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_bitwise_and() {
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            i8_as_u8(6),
+            Op::NumberI8.into(),
+            i8_as_u8(3),
+            Op::BitAnd.into(),
+        ];
+        let res = vm.interpret(&opcodes);
+        assert_eq!(res, Ok(2.0));
+    }
+
+    #[test]
+    fn test_bitwise_or() {
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            i8_as_u8(6),
+            Op::NumberI8.into(),
+            i8_as_u8(3),
+            Op::BitOr.into(),
+        ];
+        let res = vm.interpret(&opcodes);
+        assert_eq!(res, Ok(7.0));
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            i8_as_u8(1),
+            Op::NumberI8.into(),
+            i8_as_u8(4),
+            Op::ShiftLeft.into(),
+        ];
+        let res = vm.interpret(&opcodes);
+        assert_eq!(res, Ok(16.0));
+
+        vm.reset(None);
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            i8_as_u8(-16),
+            Op::NumberI8.into(),
+            i8_as_u8(2),
+            Op::ShiftRight.into(),
+        ];
+        let res = vm.interpret(&opcodes);
+        assert_eq!(res, Ok(-4.0));
+    }
+
+    #[test]
+    fn test_bitwise_invalid_operands() {
+        let mut vm = VirtualMachine::default();
+        let mut opcodes = vec![Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(1.5));
+        opcodes.push(Op::NumberI8.into());
+        opcodes.push(i8_as_u8(1));
+        opcodes.push(Op::BitAnd.into());
+        let res = vm.interpret(&opcodes);
+        assert_eq!(
+            res,
+            Err(Error::InvalidOperands {
+                op: Op::BitAnd,
+                lhs: 1.5,
+                rhs: 1.0
+            })
+        );
+
+        vm.reset(None);
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            i8_as_u8(1),
+            Op::NumberI8.into(),
+            i8_as_u8(64),
+            Op::ShiftLeft.into(),
+        ];
+        let res = vm.interpret(&opcodes);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_last_result() {
+        let mut vm = VirtualMachine::default();
+        assert_eq!(vm.last_result(), None);
+
+        let res = vm.interpret(&[Op::NumberI8.into(), i8_as_u8(4)]);
+        assert_eq!(res, Ok(4.0));
+        assert_eq!(vm.last_result(), Some(4.0));
+
+        vm.reset(None);
+        let res = vm.interpret(&[Op::NumberI8.into(), i8_as_u8(7)]);
+        assert_eq!(res, Ok(7.0));
+        assert_eq!(vm.last_result(), Some(7.0));
+
+        vm.reset(None);
+        let res = vm.interpret(&[]);
+        assert_eq!(res, Err(Error::EmptyStack));
+        assert_eq!(vm.last_result(), Some(7.0));
+    }
+
+    #[test]
+    fn test_ans_history() {
+        let mut vm = VirtualMachine::default();
+        for n in 1..=3 {
+            let res = vm.interpret(&[Op::NumberI8.into(), i8_as_u8(n)]);
+            assert_eq!(res, Ok(n as f64));
+            vm.reset(res.ok());
+        }
+        assert_eq!(vm.interpret(&[Op::AnsN.into(), 1]), Ok(3.0));
+        vm.reset(None);
+        assert_eq!(vm.interpret(&[Op::AnsN.into(), 4]), Ok(1.0));
+        vm.reset(None);
+        assert_eq!(
+            vm.interpret(&[Op::AnsN.into(), 6]),
+            Err(Error::AnsNotAvailable)
+        );
+    }
+
+    #[test]
+    fn test_step() {
+        // 1 + 2
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            1,
+            Op::NumberI8.into(),
+            2,
+            Op::Plus.into(),
+        ];
+        assert_eq!(vm.step(&opcodes), Ok(None));
+        assert_eq!(vm.stack_snapshot(), &[1.0]);
+        assert_eq!(vm.step(&opcodes), Ok(None));
+        assert_eq!(vm.stack_snapshot(), &[1.0, 2.0]);
+        assert_eq!(vm.step(&opcodes), Ok(Some(3.0)));
+        assert!(vm.stack_snapshot().is_empty());
+        assert_eq!(vm.last_result(), Some(3.0));
+    }
+
+    #[test]
+    fn test_step_empty_program() {
+        let mut vm = VirtualMachine::default();
+        assert_eq!(vm.step(&[]), Err(Error::EmptyStack));
+    }
+
+    #[test]
+    fn test_div_epsilon() {
+        // 1 / 1e-300
+        let mut opcodes = vec![Op::NumberI8.into(), 1, Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(1e-300));
+        opcodes.push(Op::Div.into());
+
+        let mut vm = VirtualMachine::default();
+        let res = vm.interpret(&opcodes);
+        assert!(res.is_ok());
+        assert_float_eq!(res.unwrap() / 1e300, 1.0f64);
+
+        let mut vm = VirtualMachine::default().with_div_epsilon(1e-12);
+        assert_eq!(vm.div_epsilon(), 1e-12);
+        assert_eq!(vm.interpret(&opcodes), Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn test_min_three_args() {
+        // min(3, -1, 4)
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            3,
+            Op::NumberI8.into(),
+            i8_as_u8(-1),
+            Op::NumberI8.into(),
+            4,
+            Op::Func.into(),
+            FuncType::Min.into(),
+            3,
+        ];
+        assert_eq!(vm.interpret(&opcodes), Ok(-1.0));
+    }
+
+    #[test]
+    fn test_max_five_args() {
+        // max(3, 1, 4, 1, 5)
+        let mut vm = VirtualMachine::default();
+        let mut opcodes = Vec::new();
+        for n in [3, 1, 4, 1, 5] {
+            opcodes.push(Op::NumberI8.into());
+            opcodes.push(n);
+        }
+        opcodes.extend([Op::Func.into(), FuncType::Max.into(), 5]);
+        assert_eq!(vm.interpret(&opcodes), Ok(5.0));
+        assert!(vm.stack_snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_sec() {
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            0,
+            Op::Func.into(),
+            FuncType::Sec.into(),
+        ];
+        assert_eq!(vm.interpret(&opcodes), Ok(1.0));
+    }
+
+    #[test]
+    fn test_csc_pole() {
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            0,
+            Op::Func.into(),
+            FuncType::Csc.into(),
+        ];
+        assert!(matches!(
+            vm.interpret(&opcodes),
+            Err(Error::InvalidFunctionArgs {
+                func_type: FuncType::Csc,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_reciprocal_trig_poles_in_degrees() {
+        let mut vm = VirtualMachine::default().with_angle_mode(AngleMode::Degrees);
+        let mut eval = |func_type: FuncType, angle: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(angle));
+            opcodes.push(Op::Func.into());
+            opcodes.push(func_type.into());
+            vm.interpret(&opcodes)
+        };
+        for (func_type, angle) in [
+            (FuncType::Sec, 90.0),
+            (FuncType::Sec, -270.0),
+            (FuncType::Csc, 180.0),
+            (FuncType::Csc, 0.0),
+            (FuncType::Cot, 180.0),
+            (FuncType::Cot, -360.0),
+        ] {
+            assert_eq!(
+                eval(func_type, angle),
+                Err(Error::InvalidFunctionArgs {
+                    func_type,
+                    func_args: FuncArgs::Arg1(angle),
+                })
+            );
+        }
+        assert_eq!(eval(FuncType::Sec, 180.0), Ok(-1.0));
+        assert_eq!(eval(FuncType::Csc, -90.0), Ok(-1.0));
+        assert_eq!(eval(FuncType::Cot, 90.0), Ok(0.0));
+        assert_float_eq!(eval(FuncType::Sec, 60.0).unwrap(), 2.0f64);
+    }
+
+    #[test]
+    fn test_cot() {
+        let mut vm = VirtualMachine::default();
+        let mut opcodes = vec![Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(1.0));
+        opcodes.push(Op::Func.into());
+        opcodes.push(FuncType::Cot.into());
+        let res = vm.interpret(&opcodes);
+        assert!(res.is_ok());
+        assert_float_eq!(res.unwrap(), 1.0f64 / 1.0f64.tan());
+    }
+
+    #[test]
+    fn test_idiv_truncates_toward_zero() {
+        let idiv = |a: i8, b: i8| {
+            let mut vm = VirtualMachine::default();
+            let opcodes = vec![
+                Op::NumberI8.into(),
+                i8_as_u8(a),
+                Op::NumberI8.into(),
+                i8_as_u8(b),
+                Op::Func.into(),
+                FuncType::IDiv.into(),
+            ];
+            vm.interpret(&opcodes)
+        };
+        assert_eq!(idiv(7, 2), Ok(3.0));
+        assert_eq!(idiv(-7, 2), Ok(-3.0));
+        assert_eq!(idiv(7, -2), Ok(-3.0));
+        assert_eq!(idiv(-7, -2), Ok(3.0));
+        // unlike flooring division
+        assert_ne!(idiv(-7, 2), Ok((-7.0f64 / 2.0).floor()));
+        assert_eq!(idiv(1, 0), Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn test_interpret_all() {
+        let mut vm = VirtualMachine::default();
+        let opcodes = vec![
+            Op::NumberI8.into(),
+            1,
+            Op::NumberI8.into(),
+            2,
+            Op::NumberI8.into(),
+            3,
+        ];
+        assert_eq!(vm.interpret_all(&opcodes), Ok(vec![1.0, 2.0, 3.0]));
+        assert!(vm.stack_snapshot().is_empty());
+        assert_eq!(vm.last_result(), None);
+    }
+
+    #[test]
+    fn test_ssqrt() {
+        let ssqrt = |x: i8| {
+            let mut vm = VirtualMachine::default();
+            let opcodes = vec![
+                Op::NumberI8.into(),
+                i8_as_u8(x),
+                Op::Func.into(),
+                FuncType::Ssqrt.into(),
+            ];
+            vm.interpret(&opcodes)
+        };
+        assert_eq!(ssqrt(-4), Ok(-2.0));
+        assert_eq!(ssqrt(9), Ok(3.0));
+        assert_eq!(ssqrt(0), Ok(0.0));
+    }
+
+    #[test]
+    fn test_angle_mode() {
+        let opcodes = [
+            Op::NumberI8.into(),
+            90,
+            Op::Func.into(),
+            FuncType::Sin.into(),
+        ];
+        let mut vm = VirtualMachine::default().with_angle_mode(AngleMode::Degrees);
+        assert_float_eq!(vm.interpret(&opcodes).unwrap(), 1.0f64);
+        vm.set_angle_mode(AngleMode::Radians);
+        assert_float_eq!(vm.interpret(&opcodes).unwrap(), math::sin(90.0));
+        assert_eq!(VirtualMachine::default().angle_mode(), AngleMode::Radians);
+    }
+
+    #[test]
+    fn test_percentage_functions() {
+        let call = |func: FuncType, a: f64, b: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(a));
+            opcodes.push(Op::Number.into());
+            opcodes.append(&mut number_to_bytes(b));
+            opcodes.extend_from_slice(&[Op::Func.into(), func.into()]);
+            VirtualMachine::default().interpret(&opcodes)
+        };
+        assert_eq!(call(FuncType::PctChange, 100.0, 125.0), Ok(25.0));
+        assert_eq!(call(FuncType::PctChange, 200.0, 150.0), Ok(-25.0));
+        assert_eq!(call(FuncType::PctOf, 1.0, 4.0), Ok(25.0));
+        assert_eq!(
+            call(FuncType::PctChange, 0.0, 1.0),
+            Err(Error::DivisionByZero)
+        );
+        assert_eq!(call(FuncType::PctOf, 1.0, 0.0), Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn test_sinc() {
+        let sinc = |x: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(x));
+            opcodes.extend_from_slice(&[Op::Func.into(), FuncType::Sinc.into()]);
+            VirtualMachine::default().interpret(&opcodes).unwrap()
+        };
+        assert_eq!(sinc(0.0), 1.0);
+        assert_float_eq!(
+            sinc(core::f64::consts::FRAC_PI_2),
+            core::f64::consts::FRAC_2_PI
+        );
+        assert_float_eq!(sinc(core::f64::consts::PI), 0.0f64);
+    }
+
+    #[test]
+    fn test_hyperbolic() {
+        let eval = |func: FuncType, x: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(x));
+            opcodes.extend_from_slice(&[Op::Func.into(), func.into()]);
+            // the angle mode does not apply
+            VirtualMachine::default()
+                .with_angle_mode(AngleMode::Degrees)
+                .interpret(&opcodes)
+                .unwrap()
+        };
+        assert_eq!(eval(FuncType::Sinh, 0.0), 0.0);
+        assert_eq!(eval(FuncType::Cosh, 0.0), 1.0);
+        assert_float_eq!(eval(FuncType::Sinh, 1.0), 1.0f64.sinh());
+        assert_float_eq!(eval(FuncType::Cosh, 1.0), 1.0f64.cosh());
+        assert_float_eq!(eval(FuncType::Tanh, 1.0), 1.0f64.tanh());
+    }
+
+    #[test]
+    fn test_fma() {
+        let mut opcodes = Vec::new();
+        for n in [0.1, 10.0, -1.0] {
+            opcodes.push(Op::Number.into());
+            opcodes.append(&mut number_to_bytes(n));
+        }
+        opcodes.extend_from_slice(&[Op::Func.into(), FuncType::Fma.into()]);
+        let fused = VirtualMachine::default().interpret(&opcodes).unwrap();
+        // 0.1 * 10 rounds to exactly 1, the fused result keeps the error of 0.1
+        assert_eq!(0.1f64 * 10.0 - 1.0, 0.0);
+        assert_eq!(fused, 5.551115123125783e-17);
+    }
+
+    #[test]
+    fn test_saturate() {
+        let saturate = |x: f64| {
+            let mut opcodes = vec![Op::Number.into()];
+            opcodes.append(&mut number_to_bytes(x));
+            opcodes.extend_from_slice(&[Op::Func.into(), FuncType::Saturate.into()]);
+            VirtualMachine::default().interpret(&opcodes)
+        };
+        assert_eq!(saturate(1.5), Ok(1.0));
+        assert_eq!(saturate(-0.3), Ok(0.0));
+        assert_eq!(saturate(0.4), Ok(0.4));
+    }
+
+    #[test]
+    fn test_inverse_trig_angle_mode() {
+        let mut vm = VirtualMachine::default().with_angle_mode(AngleMode::Degrees);
+        let atan = [
+            Op::NumberI8.into(),
+            1,
+            Op::Func.into(),
+            FuncType::Atan.into(),
+        ];
+        assert_float_eq!(vm.interpret(&atan).unwrap(), 45.0f64);
+        let asin_sin = [
+            Op::NumberI8.into(),
+            30,
+            Op::Func.into(),
+            FuncType::Sin.into(),
+            Op::Func.into(),
+            FuncType::Asin.into(),
+        ];
+        assert_float_eq!(vm.interpret(&asin_sin).unwrap(), 30.0f64);
+        let atan2 = [
+            Op::NumberI8.into(),
+            1,
+            Op::NumberI8.into(),
+            i8_as_u8(-1),
+            Op::Func.into(),
+            FuncType::Atan2.into(),
+        ];
+        assert_float_eq!(vm.interpret(&atan2).unwrap(), 135.0f64);
+        vm.set_angle_mode(AngleMode::Radians);
+        assert_float_eq!(vm.interpret(&atan).unwrap(), core::f64::consts::FRAC_PI_4);
+
+        let acos_2 = [
+            Op::NumberI8.into(),
+            2,
+            Op::Func.into(),
+            FuncType::Acos.into(),
+        ];
+        assert_eq!(
+            vm.interpret(&acos_2),
+            Err(Error::InvalidFunctionArgs {
+                func_type: FuncType::Acos,
+                func_args: super::FuncArgs::Arg1(2.0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_step_rect() {
+        let call = |func: FuncType, x: i8| {
+            let mut vm = VirtualMachine::default();
+            let opcodes = [
+                Op::NumberI8.into(),
+                i8_as_u8(x),
+                Op::Func.into(),
+                func.into(),
+            ];
+            vm.interpret(&opcodes)
+        };
+        assert_eq!(call(FuncType::Step, -1), Ok(0.0));
+        assert_eq!(call(FuncType::Step, 0), Ok(1.0));
+        assert_eq!(call(FuncType::Step, 2), Ok(1.0));
+        assert_eq!(call(FuncType::Rect, 0), Ok(1.0));
+        assert_eq!(call(FuncType::Rect, 1), Ok(0.0));
+    }
+
+    #[test]
+    fn test_tau_phi() {
+        let constant = |func: FuncType| {
+            let mut vm = VirtualMachine::default();
+            vm.interpret(&[Op::Func.into(), func.into()])
+        };
+        assert_float_eq!(
+            constant(FuncType::Tau).unwrap(),
+            2.0 * core::f64::consts::PI
+        );
+        assert_float_eq!(constant(FuncType::Phi).unwrap(), 1.618f64, 1e-3);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            Error::DivisionByZero,
+            Error::EmptyStack,
+            Error::InvalidFunctionArgs {
+                func_type: FuncType::Sqrt,
+                func_args: super::FuncArgs::Arg1(-1.0),
+            },
+            Error::AnsNotAvailable,
+            Error::InvalidOperands {
+                op: Op::Div,
+                lhs: 1.0,
+                rhs: 0.0,
+            },
+            Error::TruncatedChunk,
+            Error::InvalidOpcode(255),
+            Error::InvalidFunctionCode(255),
+            Error::InvalidConstantCode(255),
+            Error::StepLimitExceeded,
+            Error::StackUnderflow,
+            Error::UnknownExternFunction,
+            Error::ExternArity {
+                expected: 1,
+                found: 2,
+            },
+            Error::NonFiniteResult(f64::NAN),
+        ];
+        let codes: std::collections::HashSet<_> = errors.iter().map(Error::code).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert_eq!(Error::DivisionByZero.code(), "E_DIV_ZERO");
+    }
+
+    #[test]
+    fn test_seeded_rand() {
+        let opcodes = [Op::Func.into(), FuncType::Rand.into()];
+        let sequence = |mut vm: VirtualMachine| {
+            (0..8)
+                .map(|_| {
+                    vm.reset(None);
+                    vm.interpret(&opcodes).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = sequence(VirtualMachine::new_seeded(42));
+        assert_eq!(first, sequence(VirtualMachine::new_seeded(42)));
+        assert_ne!(first, sequence(VirtualMachine::new_seeded(43)));
+        assert!(first.iter().all(|x| (0.0..1.0).contains(x)));
+        assert_eq!(
+            sequence(VirtualMachine::new_seeded(0)),
+            sequence(VirtualMachine::default())
+        );
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        let mut vm = VirtualMachine::default();
+        assert_eq!(
+            vm.interpret(&[Op::Number.into(), 1, 2, 3]),
+            Err(Error::TruncatedChunk)
+        );
+        for opcodes in [
+            [Op::NumberI8.into()],
+            [Op::Func.into()],
+            [Op::AnsN.into()],
+            [Op::Const.into()],
+        ] {
+            let mut vm = VirtualMachine::default();
+            assert_eq!(vm.interpret(&opcodes), Err(Error::TruncatedChunk));
+        }
+    }
+
+    #[test]
+    fn test_malformed_chunks() {
+        let interpret = |opcodes: &[u8]| VirtualMachine::default().interpret(opcodes);
+        assert_eq!(interpret(&[255]), Err(Error::InvalidOpcode(255)));
+        assert_eq!(
+            interpret(&[Op::Func.into(), 255]),
+            Err(Error::InvalidFunctionCode(255))
+        );
+        assert_eq!(
+            interpret(&[Op::Const.into(), 255]),
+            Err(Error::InvalidConstantCode(255))
+        );
+        assert_eq!(interpret(&[Op::Plus.into()]), Err(Error::StackUnderflow));
+        assert_eq!(
+            interpret(&[Op::Func.into(), FuncType::Min.into(), 0]),
+            Err(Error::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn test_step_limit_resume() {
+        // (1 + 2) * 3
+        let opcodes = [
+            Op::NumberI8.into(),
+            1,
+            Op::NumberI8.into(),
+            2,
+            Op::Plus.into(),
+            Op::NumberI8.into(),
+            3,
+            Op::Mult.into(),
+        ];
+        let mut vm = VirtualMachine::default().with_step_limit(Some(2));
+        assert_eq!(vm.step_limit(), Some(2));
+        assert_eq!(vm.interpret(&opcodes), Err(Error::StepLimitExceeded));
+        assert_eq!(vm.stack_snapshot(), &[1.0, 2.0]);
+        assert_eq!(vm.instruction_pointer(), 4);
+        assert_eq!(vm.resume(&opcodes), Err(Error::StepLimitExceeded));
+        assert_eq!(vm.stack_snapshot(), &[3.0, 3.0]);
+        assert_eq!(vm.resume(&opcodes), Ok(9.0));
+    }
+
+    #[test]
+    fn test_two_operand_order() {
+        let interpret = |opcodes: &[u8]| VirtualMachine::default().interpret(opcodes);
+        let operands = [Op::NumberI8.into(), 2, Op::NumberI8.into(), 10];
+        let with = |tail: &[u8]| [operands.as_slice(), tail].concat();
+        assert_eq!(
+            interpret(&with(&[Op::Func.into(), FuncType::Pow.into()])),
+            Ok(1024.0)
+        );
+        assert_eq!(interpret(&with(&[Op::Minus.into()])), Ok(-8.0));
+        assert_eq!(interpret(&with(&[Op::Div.into()])), Ok(0.2));
+        assert_eq!(
+            interpret(&with(&[Op::Func.into(), FuncType::IDiv.into()])),
+            Ok(0.0)
+        );
+        assert_eq!(interpret(&with(&[Op::ShiftLeft.into()])), Ok(2048.0));
+        assert_eq!(
+            interpret(&[
+                Op::NumberI8.into(),
+                2,
+                Op::Func.into(),
+                FuncType::Pow.into()
+            ]),
+            Err(Error::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn test_interpret_twice() {
+        let opcodes = [
+            Op::NumberI8.into(),
+            2,
+            Op::NumberI8.into(),
+            3,
+            Op::Mult.into(),
+        ];
+        let mut vm = VirtualMachine::default();
+        assert_eq!(vm.interpret(&opcodes), Ok(6.0));
+        assert_eq!(vm.interpret(&opcodes), Ok(6.0));
+        // a failed run leaves operands behind, they must not leak into the next one
+        assert_eq!(
+            vm.interpret(&[Op::NumberI8.into(), 5, Op::Ans.into()]),
+            Err(Error::AnsNotAvailable)
+        );
+        assert_eq!(vm.stack_snapshot(), &[5.0]);
+        assert_eq!(vm.interpret_all(&opcodes), Ok(vec![6.0]));
+        assert_eq!(vm.interpret(&opcodes), Ok(6.0));
+    }
+
+    #[test]
+    fn test_pow_integer_exponent() {
+        let pow = |base: i8, exponent: i8| {
+            let mut vm = VirtualMachine::default();
+            vm.interpret(&[
+                Op::NumberI8.into(),
+                i8_as_u8(base),
+                Op::NumberI8.into(),
+                i8_as_u8(exponent),
+                Op::Func.into(),
+                FuncType::Pow.into(),
+            ])
+        };
+        assert_eq!(pow(10, 3), Ok(1000.0));
+        assert_eq!(pow(10, 2), Ok(100.0));
+        assert_eq!(pow(10, -2), Ok(0.01));
+        assert_eq!(pow(-2, 3), Ok(-8.0));
+        assert_eq!(pow(7, 0), Ok(1.0));
+    }
+
+    #[test]
+    fn test_halt() {
+        let opcodes = [
+            Op::NumberI8.into(),
+            4,
+            Op::Halt.into(),
+            Op::NumberI8.into(),
+            5,
+        ];
+        let mut vm = VirtualMachine::default();
+        assert_eq!(vm.interpret(&opcodes), Ok(4.0));
+        assert_eq!(vm.interpret_all(&opcodes), Ok(vec![4.0]));
+        assert_eq!(vm.interpret(&[Op::Halt.into()]), Err(Error::EmptyStack));
+    }
+
+    #[test]
+    fn test_call_extern() {
+        let mut vm = VirtualMachine::default();
+        vm.register_fn("double", 1, |args| Ok(2.0 * args[0]));
+        let call = |count: u8, name: &[u8]| {
+            let mut opcodes = vec![Op::NumberI8.into(), 21, Op::CallExtern.into(), count];
+            opcodes.push(name.len() as u8);
+            opcodes.extend_from_slice(name);
+            opcodes
+        };
+        assert_eq!(vm.interpret(&call(1, b"double")), Ok(42.0));
+        assert_eq!(
+            vm.interpret(&call(1, b"triple")),
+            Err(Error::UnknownExternFunction)
+        );
+        assert_eq!(
+            vm.interpret(&call(2, b"double")),
+            Err(Error::ExternArity {
+                expected: 1,
+                found: 2
+            })
+        );
+        assert_eq!(vm.extern_names(), ["double"]);
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(Error::DivisionByZero.to_string(), "division by zero");
+        assert_eq!(
+            Error::InvalidFunctionArgs {
+                func_type: FuncType::Sqrt,
+                func_args: super::FuncArgs::Arg1(-1.0),
+            }
+            .to_string(),
+            "invalid argument for sqrt: -1"
+        );
+        assert_eq!(
+            Error::ExternArity {
+                expected: 2,
+                found: 3,
+            }
+            .to_string(),
+            "function takes 2 argument(s), found 3"
+        );
+    }
+
+    #[test]
+    fn test_set_ans() {
+        let opcodes = [
+            Op::NumberI8.into(),
+            2,
+            Op::SetAns.into(),
+            Op::Ans.into(),
+            Op::NumberI8.into(),
+            3,
+            Op::Mult.into(),
+        ];
+        let mut vm = VirtualMachine::default();
+        assert_eq!(vm.interpret(&opcodes), Ok(6.0));
+        assert_eq!(vm.ans(), Some(2.0));
+        assert_eq!(vm.interpret(&[Op::AnsN.into(), 2]), Ok(2.0));
+        assert_eq!(
+            vm.interpret(&[Op::SetAns.into()]),
+            Err(Error::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn test_set_ans_failing_statement() {
+        // 5; ans / 0; with the second statement failing
+        let opcodes = [
+            Op::NumberI8.into(),
+            5,
+            Op::SetAns.into(),
+            Op::Ans.into(),
+            Op::NumberI8.into(),
+            0,
+            Op::Div.into(),
+        ];
+        let mut vm = VirtualMachine::default().with_ans(Some(1.0));
+        assert_eq!(vm.interpret(&opcodes), Err(Error::DivisionByZero));
+        assert_eq!(vm.ans(), Some(1.0));
+        assert_eq!(vm.last_result(), None);
+        assert_eq!(
+            vm.interpret(&[Op::AnsN.into(), 1]),
+            Err(Error::AnsNotAvailable)
+        );
+        assert_eq!(vm.interpret(&[Op::Ans.into()]), Ok(1.0));
+    }
+
+    #[test]
+    fn test_strict_nonfinite() {
+        let mut opcodes = vec![Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(1e308));
+        opcodes.extend_from_slice(&[Op::NumberI8.into(), 10, Op::Mult.into()]);
+
+        let mut vm = VirtualMachine::default();
+        assert!(!vm.strict_nonfinite());
+        assert_eq!(vm.interpret(&opcodes), Ok(f64::INFINITY));
+
+        let mut vm = VirtualMachine::default().with_strict_nonfinite(true);
+        assert!(vm.strict_nonfinite());
+        assert_eq!(
+            vm.interpret(&opcodes),
+            Err(Error::NonFiniteResult(f64::INFINITY))
+        );
+        assert_eq!(vm.last_result(), None);
+        assert_eq!(
+            vm.interpret(&[
+                Op::NumberI8.into(),
+                2,
+                Op::NumberI8.into(),
+                3,
+                Op::Mult.into()
+            ]),
+            Ok(6.0)
+        );
+    }
+
+    #[test]
+    fn test_angle_suffix_ops() {
+        let sin_90_deg = [
+            Op::NumberI8.into(),
+            90,
+            Op::Degrees.into(),
+            Op::Func.into(),
+            FuncType::Sin.into(),
+        ];
+        for mode in [AngleMode::Radians, AngleMode::Degrees] {
+            let mut vm = VirtualMachine::default().with_angle_mode(mode);
+            assert_float_eq!(vm.interpret(&sin_90_deg).unwrap(), 1.0f64);
+        }
+
+        let mut vm = VirtualMachine::default().with_angle_mode(AngleMode::Degrees);
+        let mut opcodes = vec![Op::Number.into()];
+        opcodes.append(&mut number_to_bytes(core::f64::consts::PI));
+        opcodes.push(Op::Radians.into());
+        assert_float_eq!(vm.interpret(&opcodes).unwrap(), 180.0f64);
+        vm.set_angle_mode(AngleMode::Radians);
+        assert_eq!(vm.interpret(&opcodes), Ok(core::f64::consts::PI));
+    }
 }
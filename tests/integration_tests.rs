@@ -3,7 +3,7 @@ extern crate vm_calculator;
 use vm_calculator::{
     assert_float_eq,
     compiler::{self, Compile, Compiler},
-    lexer::Lexer,
+    lexer::{self, Lexer, Span},
     vm::{self, VirtualMachine},
 };
 
@@ -21,14 +21,14 @@ fn test_addition() {
 
 #[test]
 fn test_empty() {
-    let mut lexer = Lexer::new(b"");
-    let mut compiler = Compiler::default();
-    let compiled = compiler.compile(&mut lexer);
-    assert!(compiled.is_ok());
+    for src in [b"".as_slice(), b"   "] {
+        let mut lexer = Lexer::new(src);
+        let mut compiler = Compiler::default();
+        let compiled = compiler.compile(&mut lexer);
+        assert_eq!(compiled, Err(compiler::Error::EmptyExpression));
+    }
     let mut vm = VirtualMachine::default();
-    let res = vm.interpret(compiler.opcodes());
-    assert!(res.is_err());
-    assert_eq!(res.unwrap_err(), vm::Error::EmptyStack);
+    assert_eq!(vm.interpret(&[]), Err(vm::Error::EmptyStack));
 }
 
 #[test]
@@ -92,3 +92,440 @@ fn test_expression_multiple_functions() {
     println!("{}; {}", res.unwrap(), 0.00632468f64);
     assert_float_eq!(res.unwrap(), 0.00632468f64, 1e-8);
 }
+
+#[test]
+fn test_abs_bars() {
+    let mut lexer = Lexer::new(b"|-3|");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 3.0f64);
+}
+
+#[test]
+fn test_abs_bars_in_expression() {
+    let mut lexer = Lexer::new(b"2*|1-4|");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 6.0f64);
+}
+
+#[test]
+fn test_nested_abs_bars() {
+    let mut lexer = Lexer::new(b"||-3| - 5|");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_eq!(res.unwrap(), 2.0f64);
+}
+
+#[test]
+fn test_bitwise_operators() {
+    let mut lexer = Lexer::new(b"1 << 4 | 6 & 3");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_eq!(res.unwrap(), 2.0f64);
+}
+
+#[test]
+fn test_ans_history() {
+    let mut compiler = Compiler::default();
+    let mut vm = VirtualMachine::default();
+    for src in [b"10".as_slice(), b"20", b"30"] {
+        let mut lexer = Lexer::new(src);
+        assert!(compiler.compile(&mut lexer).is_ok());
+        let res = vm.interpret(compiler.opcodes());
+        assert!(res.is_ok());
+        vm.reset(res.ok());
+        compiler.reset();
+    }
+
+    let mut lexer = Lexer::new(b"ans(1) + ans(2) / 10");
+    assert!(compiler.compile(&mut lexer).is_ok());
+    let res = vm.interpret(compiler.opcodes());
+    assert_eq!(res, Ok(32.0));
+    vm.reset(res.ok());
+    compiler.reset();
+
+    let mut lexer = Lexer::new(b"ans(2)");
+    assert!(compiler.compile(&mut lexer).is_ok());
+    assert_eq!(vm.interpret(compiler.opcodes()), Ok(30.0));
+    vm.reset(None);
+    compiler.reset();
+
+    let mut lexer = Lexer::new(b"ans(9)");
+    assert!(compiler.compile(&mut lexer).is_ok());
+    assert_eq!(
+        vm.interpret(compiler.opcodes()),
+        Err(vm::Error::AnsNotAvailable)
+    );
+}
+
+#[test]
+fn test_negated_power() {
+    let mut lexer = Lexer::new(b"-2^2");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert_eq!(res, Ok(-4.0));
+}
+
+#[test]
+fn test_power_of_negated_group() {
+    let mut lexer = Lexer::new(b"(-2)^2 * 2^-1");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert_eq!(res, Ok(2.0));
+}
+
+#[test]
+fn test_percent() {
+    let mut lexer = Lexer::new(b"50%");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 0.5f64);
+}
+
+#[test]
+fn test_percent_of_left_operand() {
+    let mut lexer = Lexer::new(b"200 + 10%");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 220.0f64);
+}
+
+#[test]
+fn test_percent_discount() {
+    let mut lexer = Lexer::new(b"200 - 10% * 2");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 199.8f64);
+}
+
+#[test]
+fn test_percent_in_group() {
+    let mut lexer = Lexer::new(b"200 + (10%)");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 200.1f64);
+}
+
+#[cfg(feature = "constants")]
+#[test]
+fn test_speed_of_light_constant() {
+    let mut lexer = Lexer::new(b"2*c");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert_eq!(res, Ok(599_584_916.0));
+}
+
+#[cfg(feature = "constants")]
+#[test]
+fn test_gravity_and_planck_constants() {
+    let mut lexer = Lexer::new(b"g / 2 + h * 1e34");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 4.903325f64 + 6.62607015f64, 1e-9);
+}
+
+#[test]
+fn test_variadic_min_max() {
+    let mut lexer = Lexer::new(b"min(3, 1, 4, 1, 5) + max(2, 7, 1)");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert_eq!(res, Ok(8.0));
+}
+
+#[test]
+fn test_compile_collecting_errors() {
+    let mut lexer = Lexer::new(b"1 + @ * 2 + sin 3");
+    let mut compiler = Compiler::default();
+    let errors = compiler.compile_collecting(&mut lexer);
+    assert_eq!(
+        errors,
+        Err(vec![
+            (
+                Some(Span { start: 4, end: 5 }),
+                compiler::Error::FromLexer(lexer::Error::InvalidChar('@'))
+            ),
+            (
+                Some(Span { start: 16, end: 17 }),
+                compiler::Error::MissingFunctionParen
+            ),
+        ])
+    );
+}
+
+#[test]
+fn test_whitespace_before_function_paren() {
+    let mut lexer = Lexer::new(b"sqrt ( 4 ) + sin (2) - sin\n(2)");
+    let mut compiler = Compiler::default();
+    let compiled = compiler.compile(&mut lexer);
+    assert!(compiled.is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 2.0f64);
+}
+
+#[test]
+fn test_zero_arity_functions() {
+    let eval = |src: &[u8]| {
+        let mut lexer = Lexer::new(src);
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut lexer).unwrap();
+        VirtualMachine::default().interpret(compiler.opcodes())
+    };
+    assert_eq!(eval(b"tau()"), Ok(core::f64::consts::TAU));
+    assert_eq!(eval(b"tau() / 2"), Ok(core::f64::consts::PI));
+    assert_float_eq!(eval(b"phi() * phi() - phi()").unwrap(), 1.0f64);
+}
+
+#[test]
+fn test_implicit_multiplication() {
+    let eval = |src: &[u8]| {
+        let mut lexer = lexer::ImplicitMul::new(Lexer::new(src));
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut lexer).unwrap();
+        VirtualMachine::default().interpret(compiler.opcodes())
+    };
+    assert_eq!(eval(b"2(3)"), Ok(6.0));
+    assert_eq!(eval(b"(1 + 1)(2 + 2)"), Ok(8.0));
+    assert_eq!(eval(b"2sqrt(4)"), Ok(4.0));
+    assert_eq!(eval(b"1 + 2(3)"), Ok(7.0));
+}
+
+#[test]
+fn test_empty_is_zero() {
+    let mut compiler = Compiler::default().with_empty_is_zero(true);
+    assert!(compiler.empty_is_zero());
+    let mut vm = VirtualMachine::default();
+    for src in [&b""[..], b"   ", b"\t\n"] {
+        compiler.reset();
+        assert_eq!(compiler.compile(&mut Lexer::new(src)), Ok(()));
+        assert_eq!(vm.interpret(compiler.opcodes()), Ok(0.0));
+    }
+    assert_eq!(vm.last_result(), Some(0.0));
+
+    let mut compiler = compiler.with_empty_is_zero(false);
+    compiler.reset();
+    assert_eq!(
+        compiler.compile(&mut Lexer::new(b"   ")),
+        Err(compiler::Error::EmptyExpression)
+    );
+    assert_eq!(vm.interpret(&[]), Err(vm::Error::EmptyStack));
+}
+
+#[test]
+fn test_minimal_chunks() {
+    let chunk_len = |src: &[u8]| {
+        let mut lexer = Lexer::new(src);
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut lexer).unwrap();
+        compiler.opcodes().len()
+    };
+    assert_eq!(chunk_len(b"42"), 2);
+    assert_eq!(chunk_len(b"4.2"), 9);
+    assert_eq!(chunk_len(b"-5"), 3);
+    assert_eq!(chunk_len(b"ans"), 1);
+    assert_eq!(chunk_len(b"ans(2)"), 2);
+}
+
+#[test]
+fn test_with_ans() {
+    let mut lexer = Lexer::new(b"ans + 1");
+    let mut compiler = Compiler::default();
+    compiler.compile(&mut lexer).unwrap();
+    let mut vm = VirtualMachine::default().with_ans(Some(41.0));
+    assert_eq!(vm.ans(), Some(41.0));
+    assert_eq!(vm.interpret(compiler.opcodes()), Ok(42.0));
+    assert_eq!(vm.ans(), Some(41.0));
+    vm.reset(None);
+    assert_eq!(vm.ans(), None);
+    assert_eq!(
+        vm.interpret(compiler.opcodes()),
+        Err(vm::Error::AnsNotAvailable)
+    );
+}
+
+#[test]
+fn test_decimal_comma() {
+    let mut lexer =
+        Lexer::new(b"pow(1,5; 2) + 0,25").with_decimal_separator(lexer::DecimalSeparator::Comma);
+    let mut compiler = Compiler::default();
+    compiler.compile(&mut lexer).unwrap();
+    let res = VirtualMachine::default().interpret(compiler.opcodes());
+    assert_eq!(res, Ok(2.5));
+}
+
+#[test]
+fn test_square_brackets() {
+    let compile = |src: &[u8]| {
+        let mut lexer = Lexer::new(src);
+        let mut compiler = Compiler::default();
+        compiler.compile(&mut lexer).map(|_| compiler)
+    };
+    let eval = |src: &[u8]| VirtualMachine::default().interpret(compile(src).unwrap().opcodes());
+    assert_eq!(eval(b"[1+2]*3"), Ok(9.0));
+    assert_eq!(eval(b"[(1 + 1) * [2 + 1]] - 1"), Ok(5.0));
+    for src in [b"(1 + 2] * 3", b"[1 + 2) * 3"] {
+        assert!(matches!(
+            compile(src),
+            Err(compiler::Error::UnterminedGroup)
+        ));
+    }
+}
+
+#[test]
+fn test_compile_with_halt() {
+    let mut lexer = Lexer::new(b"2 * (3 + 4)");
+    let mut compiler = Compiler::default().with_halt(true);
+    compiler.compile(&mut lexer).unwrap();
+    assert_eq!(
+        VirtualMachine::default().interpret(compiler.opcodes()),
+        Ok(14.0)
+    );
+}
+
+#[test]
+fn test_angle_suffix() {
+    let eval = |src: &str| {
+        let chunk = Compiler::compile_once(src).unwrap();
+        VirtualMachine::default().interpret(&chunk).unwrap()
+    };
+    assert_float_eq!(eval("sin(90deg)"), 1.0f64);
+    assert_float_eq!(eval("90deg"), core::f64::consts::FRAC_PI_2);
+    assert_float_eq!(eval("cos(0.5rad)"), eval("cos(0.5)"));
+
+    let chunk = Compiler::compile_once("sin(90deg) + asin(1) - 1.5rad").unwrap();
+    let mut vm = VirtualMachine::default().with_angle_mode(vm::AngleMode::Degrees);
+    assert_float_eq!(
+        vm.interpret(&chunk).unwrap(),
+        1.0 + 90.0 - 1.5f64.to_degrees()
+    );
+}
+
+#[test]
+fn test_register_fn() {
+    let mut vm = VirtualMachine::default();
+    vm.register_fn("double", 1, |args| Ok(2.0 * args[0]));
+    let names = vm.extern_names();
+    let mut lexer = Lexer::new(b"double(21)").with_extern_names(&names);
+    let mut compiler = Compiler::default();
+    compiler.compile(&mut lexer).unwrap();
+    let chunk = compiler.take_opcodes();
+    assert_eq!(vm.interpret(&chunk), Ok(42.0));
+}
+
+#[test]
+fn test_stack_capacity_hint() {
+    let mut lexer = Lexer::new(b"2 * (3 + sqrt(16)) - 1");
+    let mut compiler = Compiler::default();
+    compiler.compile(&mut lexer).unwrap();
+    let depth = compiler.max_stack_depth();
+    assert_eq!(depth, 3);
+    let mut vm = VirtualMachine::default().with_stack_capacity(depth);
+    assert_eq!(vm.interpret(compiler.opcodes()), Ok(13.0));
+    assert!(vm.stack_snapshot().is_empty());
+}
+
+#[test]
+fn test_last_was_integer() {
+    let mut vm = VirtualMachine::default();
+    assert!(!vm.last_was_integer());
+    vm.interpret(&Compiler::compile_once("2+2").unwrap())
+        .unwrap();
+    assert!(vm.last_was_integer());
+    vm.interpret(&Compiler::compile_once("1/3").unwrap())
+        .unwrap();
+    assert!(!vm.last_was_integer());
+}
+
+#[test]
+fn test_semicolon_threads_ans() {
+    let mut lexer = Lexer::new(b"1+1; ans*3");
+    let mut compiler = Compiler::default();
+    assert!(compiler.compile(&mut lexer).is_ok());
+    let mut vm = VirtualMachine::default();
+    assert_eq!(vm.interpret(compiler.opcodes()), Ok(6.0));
+}
+
+#[test]
+fn test_interpret_source() {
+    assert_float_eq!(
+        vm_calculator::interpret_source(b"sqrt(16) + max(1, 2)").unwrap(),
+        6.0f64
+    );
+    assert!(matches!(
+        vm_calculator::interpret_source(b"1 @"),
+        Err(vm_calculator::EvalError::Compiler(_))
+    ));
+}
+
+#[test]
+fn test_gcd() {
+    assert_eq!(vm_calculator::interpret_source(b"gcd(12, 18)"), Ok(6.0));
+    assert!(matches!(
+        vm_calculator::interpret_source(b"gcd(1.5, 2)"),
+        Err(vm_calculator::EvalError::VirtualMachine(
+            vm::Error::InvalidFunctionArgs { .. }
+        ))
+    ));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_eval_reader() {
+    let src = std::io::Cursor::new(b"2 * 3\nans + 1\n");
+    assert_eq!(vm_calculator::eval_reader(src).ok(), Some(7.0));
+    let err = vm_calculator::eval_reader(std::io::Cursor::new(b"1 / 0")).unwrap_err();
+    assert!(matches!(
+        err,
+        vm_calculator::ApplicationError::VirtualmachineError { .. }
+    ));
+}
@@ -0,0 +1,43 @@
+//! Exercises the library core on its own, meant to be run against a `no_std` build with
+//! `cargo test --no-default-features --features libm --test no_std_smoke`.
+
+extern crate vm_calculator;
+
+use vm_calculator::{
+    assert_float_eq,
+    compiler::{Compile, Compiler, Op},
+    lexer::{FuncType, Lexer},
+    vm::VirtualMachine,
+};
+
+#[test]
+fn test_constant_chunk() {
+    let opcodes = [
+        Op::NumberI8.into(),
+        2,
+        Op::NumberI8.into(),
+        10,
+        Op::Func.into(),
+        FuncType::Pow.into(),
+        Op::NumberI8.into(),
+        4,
+        Op::Func.into(),
+        FuncType::Sqrt.into(),
+        Op::Div.into(),
+    ];
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(&opcodes);
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 512.0f64);
+}
+
+#[test]
+fn test_compiled_source() {
+    let mut lexer = Lexer::new(b"round_to(log(sin(1) + cos(1)), 3)");
+    let mut compiler = Compiler::default();
+    assert!(compiler.compile(&mut lexer).is_ok());
+    let mut vm = VirtualMachine::default();
+    let res = vm.interpret(compiler.opcodes());
+    assert!(res.is_ok());
+    assert_float_eq!(res.unwrap(), 0.323f64);
+}
@@ -0,0 +1,72 @@
+extern crate vm_calculator;
+
+use proptest::prelude::*;
+use vm_calculator::{
+    compiler::{write_f64_operand, Compile, Compiler, Op},
+    lexer::{FuncType, Lexer},
+    vm::VirtualMachine,
+};
+
+/// The opcodes are numbered from 0 without gaps, so any new `Op` is covered.
+fn op_count() -> u8 {
+    (0u8..=u8::MAX)
+        .take_while(|&byte| Op::try_from(byte).is_ok())
+        .count() as u8
+}
+
+/// One instruction with operand bytes that are usually, but not always, valid.
+/// Shrinking goes towards fewer instructions and smaller bytes.
+fn instruction() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        (0..op_count()).prop_map(|op| vec![op]),
+        any::<u8>().prop_map(|byte| vec![Op::NumberI8.into(), byte]),
+        any::<f64>().prop_map(|n| {
+            let mut bytes = vec![Op::Number.into()];
+            bytes.extend_from_slice(&write_f64_operand(n));
+            bytes
+        }),
+        (0..FuncType::all().count() as u8 + 2, any::<u8>()).prop_map(|(func, count)| vec![
+            Op::Func.into(),
+            func,
+            count
+        ]),
+        any::<u8>().prop_map(|byte| vec![byte]),
+    ]
+}
+
+fn chunk() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(instruction(), 0..32).prop_map(|instructions| instructions.concat())
+}
+
+proptest! {
+    #[test]
+    fn interpret_arbitrary_bytes_never_panics(opcodes in prop::collection::vec(any::<u8>(), 0..64)) {
+        let _ = VirtualMachine::default().interpret(&opcodes);
+    }
+
+    #[test]
+    fn interpret_arbitrary_chunk_never_panics(opcodes in chunk()) {
+        let _ = VirtualMachine::default().interpret(&opcodes);
+        let _ = VirtualMachine::default().interpret_all(&opcodes);
+    }
+
+    #[test]
+    fn compile_and_interpret_ascii_never_panics(src in "[ -~]{0,48}") {
+        let mut lexer = Lexer::new(src.as_bytes());
+        let mut compiler = Compiler::default();
+        if compiler.compile(&mut lexer).is_ok() {
+            let _ = VirtualMachine::default().interpret(compiler.opcodes());
+        }
+    }
+
+    #[test]
+    fn compile_and_interpret_expression_like_never_panics(
+        src in r"[0-9a-z+\-*/^%|&<>(), .]{0,48}"
+    ) {
+        let mut lexer = Lexer::new(src.as_bytes());
+        let mut compiler = Compiler::default();
+        if compiler.compile(&mut lexer).is_ok() {
+            let _ = VirtualMachine::default().interpret(compiler.opcodes());
+        }
+    }
+}